@@ -1,42 +1,115 @@
+mod e2e;
+mod error;
 #[allow(dead_code)]
 mod problem;
 mod proto;
 mod util;
 
+use crate::e2e::{Direction, E2eKey};
+use crate::error::RtalError;
 use crate::proto::{Reply, Request};
 use crate::util::send_binary_data;
-use clap::{ArgEnum, Parser, Subcommand};
+use chrono::{DateTime, FixedOffset, Utc};
+use clap::{ArgEnum, Args, Parser, Subcommand};
 use futures_util::sink::Sink;
 use futures_util::stream::Stream;
 use futures_util::{SinkExt, StreamExt};
+use hmac::{Hmac, Mac};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
 use regex::Regex;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use rustls::version::{TLS12, TLS13};
+use rustls::{
+    ClientConfig, OwnedTrustAnchor, ProtocolVersion, RootCertStore, ServerName,
+    SupportedProtocolVersion,
+};
+use semver::{Version, VersionReq};
+use sha2::Sha256;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::fmt::Display;
-use std::path::PathBuf;
+use std::hash::{Hash, Hasher};
+use std::io::IsTerminal;
+use std::path::{Component, Path, PathBuf};
 use std::process::Stdio;
 use std::str::FromStr;
-use tokio::io::{stdin, stdout, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{stdin, stdout, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::net::{lookup_host, TcpListener, TcpSocket, TcpStream};
 use tokio::process as proc;
 use tokio::runtime::Runtime;
 use tokio::select;
-use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio::signal::ctrl_c;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{
+    interval, sleep, sleep_until, timeout, timeout_at, Duration, Instant as TokioInstant,
+};
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::{
+    Request as ClientRequest, Response as ClientResponse,
+};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Message};
 use tokio_tungstenite::tungstenite::Error as TsError;
-use tokio_tungstenite::{connect_async, MaybeTlsStream};
-use tracing::{error, warn};
-use util::recv_binary_data;
+use tokio_tungstenite::{
+    accept_async, client_async, connect_async, connect_async_tls_with_config, Connector,
+    MaybeTlsStream, WebSocketStream,
+};
+use tracing::{debug, error, info, warn};
+use url::Url;
+use util::{recv_binary_file, RecvFileError};
 
 #[cfg(unix)]
 use {
     nix::{
+        sys::signal::{kill, killpg, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
         sys::stat::Mode,
-        unistd::{mkfifo, Pid},
+        unistd::{mkfifo, setpgid, Pid},
     },
     tempfile::tempdir,
+    tokio::net::UnixStream,
+    tokio::signal::unix::{signal, SignalKind},
     tokio::{fs::OpenOptions, join},
 };
 
+// Process creation flags from the Windows API
+#[cfg(windows)]
+const NORMAL_PRIORITY_CLASS: u32 = 0x0000_0020;
+#[cfg(windows)]
+const IDLE_PRIORITY_CLASS: u32 = 0x0000_0040;
+#[cfg(windows)]
+const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+#[cfg(windows)]
+const ABOVE_NORMAL_PRIORITY_CLASS: u32 = 0x0000_8000;
+
 const BUFFER_SIZE: usize = 1 << 16;
+const DEFAULT_SERVER_URL: &str = "ws://127.0.0.1:8008/";
+const DEFAULT_SERVICE: &str = "solve";
+const AUTO_SEED: &str = "auto";
+const ECHO_WRAP_INDENT: &str = "  ";
+// CSI sequences, OSC sequences ended by BEL or ST, and two byte escapes
+const ANSI_ESCAPE: &str =
+    r"\x1b\[[0-9:;<=>?]*[ -/]*[@-~]|\x1b\][^\x07\x1b]*(?:\x07|\x1b\\)|\x1b[@-Z\\-_]";
+// The same sequences cut at the end of a message
+const ANSI_CUT: &str = r"\x1b(?:\[[0-9:;<=>?]*[ -/]*|\][^\x07\x1b]*\x1b?)?$";
+const ANSI_MAX_HELD: usize = 256;
+const TERM_GRACE_PERIOD: f64 = 5.0;
+// Seconds past --deadline left to the session to stop the program before it is cut short
+const DEADLINE_SLACK: f64 = TERM_GRACE_PERIOD + 1.0;
+const LOOP_WINDOW: f64 = 5.0;
+const MIRROR_QUEUE: usize = 1024;
+const ECHO_QUEUE: usize = 256;
+const ECHO_DRAIN_TIME: f64 = 1.0;
+const WATCH_POLL: f64 = 0.5;
+const WATCH_DEBOUNCE: f64 = 0.3;
+const PROBE_TIMEOUT: f64 = 10.0;
+const PROBE_MIN_FRAME_SIZE: usize = 64 << 10;
+const PROBE_MAX_FRAME_SIZE: usize = 16 << 20;
 
 fn parse_key_val<T, U>(s: &str) -> Result<(T, U), Box<dyn Error + Send + Sync + 'static>>
 where
@@ -51,6 +124,85 @@ where
     Ok((s[..pos].parse()?, s[pos + 1..].parse()?))
 }
 
+fn parse_nice(s: &str) -> Result<i32, String> {
+    match s.parse() {
+        Ok(x) if (-20..=19).contains(&x) => Ok(x),
+        _ => Err(format!(
+            "niceness must be an integer from -20 to 19, got `{}`",
+            s
+        )),
+    }
+}
+
+/// Parses a number of seconds that `Duration::from_secs_f64` accepts.
+fn parse_seconds(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(x) if x.is_finite() && x >= 0.0 && x < u64::MAX as f64 => Ok(x),
+        _ => Err(format!(
+            "expected a non-negative number of seconds, got `{}`",
+            s
+        )),
+    }
+}
+
+/// Parses a size in MiB for --limit-mem, returning it in bytes.
+fn parse_mebibytes(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>().ok().and_then(|x| x.checked_mul(1 << 20)) {
+        Some(x) => Ok(x),
+        None => Err(format!(
+            "expected a size in MiB up to {}, got `{}`",
+            u64::MAX >> 20,
+            s
+        )),
+    }
+}
+
+/// Parses `hex:0d0a` style byte strings, or text with the `\n`, `\r`, `\t`, `\0`, `\\` and
+/// `\xNN` escapes.
+fn parse_frame_marker(s: &str) -> Result<Box<[u8]>, String> {
+    if let Some(hex) = s.strip_prefix("hex:") {
+        return (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|x| u8::from_str_radix(x, 16).ok())
+            })
+            .collect::<Option<Box<[u8]>>>()
+            .ok_or_else(|| format!("invalid hex byte string `{}`", hex));
+    }
+    let mut bytes = Vec::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(x) if hex.len() == 2 => bytes.push(x),
+                    _ => return Err(format!("invalid escape `\\x{}` in `{}`", hex, s)),
+                }
+            }
+            x => {
+                return Err(format!(
+                    "invalid escape `\\{}` in `{}`",
+                    x.unwrap_or(' '),
+                    s
+                ))
+            }
+        }
+    }
+    Ok(bytes.into())
+}
+
 fn parse_key_val_optional<T, U>(s: &str) -> Result<(T, U), Box<dyn Error + Send + Sync + 'static>>
 where
     T: FromStr,
@@ -71,14 +223,126 @@ struct CliArgs {
     #[clap(
         short,
         long,
-        help = "Server URL",
-        default_value = "ws://127.0.0.1:8008/"
+        help = "Server URL (a comma-separated list is tried in order until one connects) [default: ws://127.0.0.1:8008/]"
+    )]
+    server_url: Option<String>,
+    #[clap(long, help = "Try the server URLs in random order")]
+    random_server: bool,
+    #[clap(
+        arg_enum,
+        long,
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "redacted",
+        help = "Print the WebSocket upgrade request before sending it"
+    )]
+    print_request: Option<PrintRequest>,
+    #[clap(
+        long,
+        help = "Print the negotiated WebSocket extensions, TLS parameters and message size limits"
+    )]
+    print_negotiated: bool,
+    #[clap(arg_enum, long, help = "Minimum TLS version accepted from the server")]
+    min_tls: Option<TlsVersion>,
+    #[clap(
+        long,
+        alias = "tls-expected-name",
+        help = "Hostname to send as SNI and to validate the server certificate against"
     )]
-    server_url: String,
+    tls_sni: Option<String>,
+    #[clap(
+        long,
+        value_name = "HOST:PORT",
+        help = "Open the connection to this address while keeping the URL host for Host and SNI"
+    )]
+    connect_to: Option<String>,
+    #[clap(long, help = "Request this SO_SNDBUF size in bytes for the connection")]
+    so_sndbuf: Option<u32>,
+    #[clap(long, help = "Request this SO_RCVBUF size in bytes for the connection")]
+    so_rcvbuf: Option<u32>,
+    #[clap(
+        long,
+        parse(try_from_str = VersionReq::parse),
+        help = "Refuse servers whose version does not satisfy this semver requirement (e.g. \">=0.2, <0.3\")"
+    )]
+    require_server_version: Option<VersionReq>,
+    #[clap(
+        long,
+        help = "Write a Prometheus snapshot of the run metrics to this file when done"
+    )]
+    metrics_file: Option<PathBuf>,
+    #[clap(
+        long,
+        default_value = "1000",
+        value_name = "CODE[:REASON]",
+        help = "Close code and reason sent to the server when the command succeeds"
+    )]
+    close_normal: CloseSpec,
+    #[clap(
+        long,
+        default_value = "1001:client interrupted",
+        value_name = "CODE[:REASON]",
+        help = "Close code and reason sent to the server on Ctrl-C"
+    )]
+    close_interrupt: CloseSpec,
+    #[clap(
+        long,
+        default_value = "4000:client timeout",
+        value_name = "CODE[:REASON]",
+        help = "Close code and reason sent to the server when a timeout or the deadline ends the session"
+    )]
+    close_timeout: CloseSpec,
+    #[clap(
+        long,
+        default_value = "4001:client error",
+        value_name = "CODE[:REASON]",
+        help = "Close code and reason sent to the server when the command fails"
+    )]
+    close_error: CloseSpec,
+    #[clap(
+        long,
+        help = "Print a colored summary line with the close code and reason at the end of the session"
+    )]
+    color_verdict: bool,
+    #[clap(
+        long,
+        help = "Answer the server authentication challenge with an HMAC keyed by this file"
+    )]
+    auth_key_file: Option<PathBuf>,
     #[clap(subcommand)]
     command: Command,
 }
 
+/// Close frame contents, parsed from `CODE[:REASON]`.
+#[derive(Debug, Clone)]
+struct CloseSpec {
+    code: u16,
+    reason: String,
+}
+
+impl FromStr for CloseSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (code, reason) = s.split_once(':').unwrap_or((s, ""));
+        // The close frame payload is at most 125 bytes, two of them for the code
+        if reason.len() > 123 {
+            return Err(format!("close reason longer than 123 bytes: `{}`", reason));
+        }
+        // 1004-1006 and 1015 are reserved for the endpoints, 1016-2999 are not assigned
+        match code.parse() {
+            Ok(x @ (1000..=1003 | 1007..=1014 | 3000..=4999)) => Ok(CloseSpec {
+                code: x,
+                reason: reason.to_string(),
+            }),
+            _ => Err(format!(
+                "expected a close code in 1000-1003, 1007-1014 or 3000-4999, found `{}`",
+                code
+            )),
+        }
+    }
+}
+
 #[derive(ArgEnum, Debug, Clone, PartialEq, Eq)]
 enum CommunicationChannel {
     Stdio,
@@ -86,6 +350,44 @@ enum CommunicationChannel {
     Pipe,
 }
 
+#[derive(ArgEnum, Debug, Clone, PartialEq, Eq)]
+enum PrintRequest {
+    Redacted,
+    Full,
+}
+
+#[derive(ArgEnum, Debug, Clone, PartialEq, Eq)]
+enum TlsVersion {
+    #[clap(name = "1.2")]
+    Tls12,
+    #[clap(name = "1.3")]
+    Tls13,
+}
+
+/// What happens to the local program when the session ends with an error.
+#[derive(ArgEnum, Debug, Clone, PartialEq, Eq)]
+enum SessionEnd {
+    /// Kill the program right away (TerminateProcess on Windows)
+    Kill,
+    /// Send SIGTERM and kill the program if it is still running after a grace period (same as kill on Windows)
+    TermThenKill,
+    /// Leave the program running
+    Detach,
+}
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum EchoOverflow {
+    Drop,
+    Block,
+}
+
+#[derive(ArgEnum, Debug, Clone, PartialEq, Eq)]
+enum EchoFilter {
+    In,
+    Out,
+    Both,
+}
+
 #[derive(ArgEnum, Debug, Clone, PartialEq, Eq)]
 enum Color {
     Auto,
@@ -93,6 +395,229 @@ enum Color {
     Off,
 }
 
+impl Color {
+    /// Auto colors a terminal stdout unless `NO_COLOR` is set.
+    fn enabled(&self) -> bool {
+        match self {
+            Color::Auto => {
+                std::env::var_os("NO_COLOR").unwrap_or_default().is_empty()
+                    && std::io::stdout().is_terminal()
+            }
+            Color::Off => false,
+            Color::On => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct VerdictKeys {
+    result: String,
+    score: String,
+    message: String,
+}
+
+impl FromStr for VerdictKeys {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split(',').collect::<Vec<_>>()[..] {
+            [result, score, message] => Ok(VerdictKeys {
+                result: result.to_string(),
+                score: score.to_string(),
+                message: message.to_string(),
+            }),
+            _ => Err(format!("expected RESULT,SCORE,MESSAGE, found `{}`", s)),
+        }
+    }
+}
+
+/// Artificial network trouble for --chaos, parsed from `latency=MS,jitter=MS,drop=P,seed=N`.
+#[derive(Debug, Clone, Default)]
+struct Chaos {
+    latency: u64,
+    jitter: u64,
+    drop: f64,
+    seed: Option<u64>,
+}
+
+impl FromStr for Chaos {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chaos = Chaos::default();
+        for item in s.split(',') {
+            let (key, value) = match item.split_once('=') {
+                Some(x) => x,
+                None => return Err(format!("expected KEY=value, found `{}`", item)),
+            };
+            let invalid = || format!("invalid value for {}: `{}`", key, value);
+            match key {
+                "latency" => chaos.latency = value.parse().map_err(|_| invalid())?,
+                "jitter" => chaos.jitter = value.parse().map_err(|_| invalid())?,
+                "drop" => chaos.drop = value.parse().map_err(|_| invalid())?,
+                "seed" => chaos.seed = Some(value.parse().map_err(|_| invalid())?),
+                _ => return Err(format!("unknown chaos parameter `{}`", key)),
+            }
+        }
+        if !(0.0..=1.0).contains(&chaos.drop) {
+            return Err(format!(
+                "drop must be a probability, found `{}`",
+                chaos.drop
+            ));
+        }
+        Ok(chaos)
+    }
+}
+
+impl Chaos {
+    /// Picks the artificial latency of a message, or tells that the message is lost.
+    fn plan(&self, rng: &mut StdRng) -> Option<Duration> {
+        if rng.gen_bool(self.drop) {
+            return None;
+        }
+        Some(Duration::from_millis(
+            self.latency + rng.gen_range(0..=self.jitter),
+        ))
+    }
+}
+
+/// Queues a message held back by --chaos, never ahead of those already waiting so that the
+/// messages keep their order.
+fn delay_message(queue: &mut VecDeque<(TokioInstant, Vec<u8>)>, delay: Duration, data: Vec<u8>) {
+    let mut release = TokioInstant::now() + delay;
+    if let Some((last, _)) = queue.back() {
+        release = release.max(*last);
+    }
+    queue.push_back((release, data));
+}
+
+#[derive(ArgEnum, Debug, Clone, PartialEq, Eq)]
+enum Charset {
+    Ascii,
+    Printable,
+    Utf8,
+}
+
+/// Contract each program line must respect before it is sent to the server.
+#[derive(Debug, Clone, Default)]
+struct OutboundRules {
+    max_size: Option<usize>,
+    prefix: Option<String>,
+    terminator: Option<String>,
+    charset: Option<Charset>,
+}
+
+/// Sentinel bytes delimiting the messages of services that do not rely on frame boundaries.
+#[derive(Debug, Clone, Default)]
+struct Framing {
+    prefix: Vec<u8>,
+    suffix: Vec<u8>,
+    strip_inbound: bool,
+}
+
+impl Framing {
+    fn wrap(&self, data: Vec<u8>) -> Vec<u8> {
+        if self.prefix.is_empty() && self.suffix.is_empty() {
+            return data;
+        }
+        let mut framed = self.prefix.clone();
+        framed.extend_from_slice(&data);
+        if !data.ends_with(&self.suffix) {
+            framed.extend_from_slice(&self.suffix);
+        }
+        framed
+    }
+
+    fn strip(&self, data: Vec<u8>) -> Vec<u8> {
+        if !self.strip_inbound {
+            return data;
+        }
+        let inner = data.strip_prefix(&self.prefix[..]).unwrap_or(&data);
+        let inner = inner.strip_suffix(&self.suffix[..]).unwrap_or(inner);
+        inner.to_vec()
+    }
+}
+
+impl OutboundRules {
+    fn is_set(&self) -> bool {
+        self.max_size.is_some()
+            || self.prefix.is_some()
+            || self.terminator.is_some()
+            || self.charset.is_some()
+    }
+
+    fn check(&self, data: &[u8]) -> Result<(), String> {
+        if let Some(max) = self.max_size {
+            if data.len() > max {
+                return Err(format!(
+                    "is {} bytes long, above the {} allowed",
+                    data.len(),
+                    max
+                ));
+            }
+        }
+        if let Some(ref prefix) = self.prefix {
+            if !data.starts_with(prefix.as_bytes()) {
+                return Err(format!("does not start with {:?}", prefix));
+            }
+        }
+        if let Some(ref terminator) = self.terminator {
+            if !data.ends_with(terminator.as_bytes()) {
+                return Err(format!("does not end with {:?}", terminator));
+            }
+        }
+        let bad = match self.charset {
+            Some(Charset::Ascii) => data.iter().position(|x| !x.is_ascii()),
+            Some(Charset::Printable) => data
+                .iter()
+                .position(|x| !(x.is_ascii_graphic() || b" \t\r\n".contains(x))),
+            Some(Charset::Utf8) => std::str::from_utf8(data).err().map(|x| x.valid_up_to()),
+            None => None,
+        };
+        match bad {
+            Some(x) => Err(format!(
+                "has byte 0x{:02x} at offset {} outside the {:?} charset",
+                data[x],
+                x,
+                self.charset.as_ref().expect("Cannot fail")
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SessionOptions {
+    echo: bool,
+    colored: bool,
+    verdict: Option<VerdictKeys>,
+    normalize_newlines: bool,
+    eof_marker: Option<String>,
+    max_frame_payload: Option<usize>,
+    max_first_response: Option<u64>,
+    echo_wrap: Option<usize>,
+    echo_filter: EchoFilter,
+    echo_strip_ansi: bool,
+    echo_overflow: EchoOverflow,
+    idle_warning: Option<f64>,
+    deadline: Option<TokioInstant>,
+    line_mode: bool,
+    loop_limit: Option<u32>,
+    strict_utf8: bool,
+    chaos: Option<Chaos>,
+    fail_fast: Option<Regex>,
+    post_connect_delay: Option<u64>,
+    crash_grace: Option<u64>,
+    debug_seq_flush: Option<f64>,
+    ping_interval: Option<u64>,
+    ping_failures: u32,
+    pausable: bool,
+    pause_inbound: bool,
+    outbound: OutboundRules,
+    framing: Framing,
+    e2e: Option<Arc<E2eKey>>,
+}
+
 #[derive(Subcommand, Debug, Clone)]
 enum Command {
     /// List available problems
@@ -109,261 +634,804 @@ enum Command {
     },
     /// Download problem attachments
     Get {
-        #[clap(help = "Name of the problem to download the attachments")]
-        problem: String,
+        #[clap(
+            required = true,
+            help = "Names of the problems to download the attachments, over a single connection"
+        )]
+        problems: Vec<String>,
         #[clap(
             short,
             long,
-            help = "Path to output the attachments [default: <problem_name>.tar]"
+            help = "Path to output the attachments, a template with {problem} and {name} placeholders when downloading more than one problem [default: <problem_name>.tar]"
         )]
         output: Option<String>,
+        #[clap(
+            long,
+            help = "Directory that the expanded --output templates must stay within",
+            default_value = "."
+        )]
+        base_dir: PathBuf,
+        #[clap(
+            long,
+            help = "Do not ask the server to compress the attachments in transit"
+        )]
+        no_compress: bool,
     },
     /// Connect to problem evaluator
-    Connect {
-        #[clap(short, long, help = "Echo messages on console")]
-        echo: bool,
+    Connect(Box<ConnectArgs>),
+    /// Measure latency, throughput and frame size limits towards the server
+    Probe {
         #[clap(
-            arg_enum,
-            short = 'k',
+            short,
             long,
-            help = "Disable colored output",
-            default_value = "auto"
+            help = "Number of pings used to measure latency",
+            default_value = "5"
         )]
-        color: Color,
-        #[clap(short = 'x', long, help = "Authentication token")]
-        auth_token: Option<String>,
+        pings: u32,
         #[clap(
-            arg_enum,
             short,
             long,
-            help = "Channel for program communication",
-            default_value = "stdio"
+            help = "Size of the largest frame to send (in KiB, at most 16384)",
+            default_value = "1024"
         )]
-        channel: CommunicationChannel,
-        #[clap(help = "Remote problem to connect to")]
+        frame_size: usize,
+    },
+    /// Measure sustained throughput and latency percentiles of an echo service
+    Bench {
+        #[clap(help = "Problem name")]
         problem: String,
-        #[clap(help = "Service wanted", default_value = "solve")]
+        #[clap(
+            short,
+            long,
+            help = "Service echoing back what it receives",
+            default_value = "echo"
+        )]
         service: String,
-        #[clap(short = 'a', long, multiple_occurrences(true), parse(try_from_str = parse_key_val_optional), help = "Service arguments, can be specified multiple times with -a arg=val")]
-        service_arg: Vec<(String, String)>,
-        #[clap(short = 'f', long, multiple_occurrences(true), parse(try_from_str = parse_key_val), help = "File arguments, can be specified multiple times with -f arg=file")]
-        file_arg: Vec<(String, String)>,
         #[clap(
             short,
             long,
-            help = "Path to output the attachments",
-            default_value = "./output/"
+            help = "Size of every message in bytes, the last one being a newline",
+            default_value = "1024"
+        )]
+        payload_size: usize,
+        #[clap(
+            short,
+            long,
+            help = "Seconds spent sending messages",
+            default_value = "10",
+            parse(try_from_str = parse_seconds)
         )]
-        output: PathBuf,
-        #[clap(help = "Local executable and executable arguments", raw = true)]
-        program: Vec<String>,
+        duration: f64,
+        #[clap(
+            short,
+            long,
+            help = "Messages waiting for their echo at the same time",
+            default_value = "1"
+        )]
+        concurrency: usize,
+    },
+    /// Relay a client connecting here to the server, printing everything they exchange
+    Relay {
+        #[clap(
+            short,
+            long,
+            help = "Address where the client connects",
+            default_value = "127.0.0.1:8009"
+        )]
+        listen: String,
+        #[clap(
+            long,
+            help = "Also write every relayed message to this file, in the --debug-seq format"
+        )]
+        log: Option<PathBuf>,
     },
 }
 
-impl Command {
-    async fn run<T: Sink<Message> + Unpin, U: Stream<Item = Result<Message, TsError>> + Unpin>(
-        self,
-        wsout: &mut T,
-        wsin: &mut U,
-        ask_to_exit: &mut bool,
-    ) -> Result<(), String>
-    where
-        <T as Sink<Message>>::Error: Display,
-    {
-        match self {
-            Command::List {
-                filter,
-                mut verbose,
-            } => {
-                let request = Request::MetaList {};
-                let reply = match oneshot_request(request, wsout, wsin).await? {
-                    Reply::MetaList { meta } => meta,
-                    _ => return Err(format!("Server sent an invalid response")),
-                };
-                verbose += if filter.is_some() { 1 } else { 0 };
-                let meta: BTreeMap<_, _> = reply.into_iter().collect();
-                for (name, meta) in meta.into_iter() {
-                    if let Some(filter) = &filter {
-                        if !filter.is_match(&name) {
-                            continue;
-                        }
-                    }
-                    println!("- {}", name);
-                    if verbose >= 1 {
-                        let services: BTreeMap<_, _> = meta.services.into_iter().collect();
-                        for (servname, servargs) in services.into_iter() {
-                            println!("  * {}", servname);
-                            if let Some(args) = servargs.args {
-                                let args: BTreeMap<_, _> = args.into_iter().collect();
-                                for (argname, arg) in args.into_iter() {
-                                    if verbose >= 2 {
-                                        if let Some(def) = arg.default {
-                                            println!("    # {} [{}] {}", argname, def, arg.regex);
-                                        } else {
-                                            println!("    # {} {}", argname, arg.regex);
-                                        }
-                                    } else {
-                                        if let Some(def) = arg.default {
-                                            println!("    # {} [{}]", argname, def);
-                                        } else {
-                                            println!("    # {}", argname);
-                                        }
-                                    }
-                                }
-                            }
-                            if let Some(mut args) = servargs.files {
-                                args.sort();
-                                for arg in args.into_iter() {
-                                    println!("    § {}", arg);
-                                }
-                            }
-                        }
-                    }
-                }
-                Ok(())
-            }
-            Command::Get { problem, output } => {
-                let request = Request::Attachment { problem };
-                match oneshot_request(request, wsout, wsin).await? {
-                    Reply::Attachment { status: Ok(()) } => {}
-                    Reply::Attachment { status: Err(x) } => {
-                        return Err(format!("Cannot download attachment: {}", x))
-                    }
-                    _ => return Err(format!("Server sent an invalid response")),
-                };
-                let (name, data) = match recv_binary_data(wsin).await {
-                    Ok(x) => x,
-                    Err(x) => return Err(format!("Error while downloading the attachment: {}", x)),
-                };
-                let name = output.unwrap_or(name);
-                tokio::fs::write(name, data)
-                    .await
-                    .map_err(|x| format!("Error while writing attachment to disk: {}", x))
-            }
-            Command::Connect {
-                echo,
-                color,
-                auth_token,
-                channel,
-                problem,
-                service,
-                service_arg,
-                file_arg,
-                output,
-                program,
-            } => {
-                let request = Request::ConnectBegin {
-                    problem,
-                    service,
-                    args: service_arg.into_iter().collect(),
-                    tty: match color {
-                        Color::Auto => program.len() == 0 && channel == CommunicationChannel::Stdio,
-                        Color::Off => false,
-                        Color::On => true,
-                    },
-                    token: auth_token,
-                    files: file_arg.iter().map(|x| x.0.clone()).collect(),
-                };
-                let allowed_files = match oneshot_request(request, wsout, wsin).await? {
-                    Reply::ConnectBegin { status: Ok(x) } => x,
-                    Reply::ConnectBegin { status: Err(x) } => {
-                        return Err(format!("Cannot connect: {}", x))
-                    }
-                    _ => return Err(format!("Server sent an invalid response")),
-                };
-                let provided_files: HashMap<_, _> = file_arg.into_iter().collect();
-                let allowed_files: HashSet<_> = allowed_files.into_iter().collect();
-                let mut to_upload = Vec::new();
-                for (name, path) in provided_files {
-                    if !allowed_files.contains(&name) {
-                        return Err(format!("File \"{}\" is not allowed", &name));
-                    }
-                    match tokio::fs::read(&path).await {
-                        Ok(x) => to_upload.push((name, x)),
-                        Err(x) => return Err(format!("Cannot read {}: {}", path, x)),
-                    }
-                }
-                for (name, data) in to_upload {
-                    if let Err(x) = send_binary_data(wsout, &name, &data).await {
-                        return Err(format!("Cannot send input file: {}", x));
-                    }
-                }
-                match oneshot_reply(wsin).await? {
-                    Reply::ConnectStart { status: Ok(()) } => {}
-                    Reply::ConnectStart { status: Err(x) } => {
-                        return Err(format!("Cannot start connection: {}", x))
-                    }
-                    _ => return Err(format!("Server sent an invalid response")),
-                };
-                // BEGIN: Handle channels
-                let output_files = match channel {
-                    CommunicationChannel::Stdio => {
-                        if program.len() > 0 {
-                            let mut prog = proc::Command::new(&program[0]);
-                            if program.len() > 1 {
-                                prog.args(&program[1..]);
-                            }
-                            prog.stdout(Stdio::piped());
-                            prog.stdin(Stdio::piped());
-                            let mut prog = match prog.spawn() {
-                                Ok(x) => x,
-                                Err(x) => return Err(format!("Cannot spawn program: {}", x)),
-                            };
+#[derive(Args, Debug, Clone)]
+struct ConnectArgs {
+    #[clap(short, long, help = "Echo messages on console")]
+    echo: bool,
+    #[clap(long, help = "Wrap echoed lines longer than this many columns")]
+    echo_wrap: Option<usize>,
+    #[clap(
+        arg_enum,
+        long,
+        default_value = "both",
+        help = "Echo only what the server sends (in), only what the program sends (out) or both"
+    )]
+    echo_filter: EchoFilter,
+    #[clap(long, help = "Remove ANSI escape sequences from the echoed text")]
+    echo_strip_ansi: bool,
+    #[clap(
+        arg_enum,
+        long,
+        help = "When the terminal cannot keep up, drop echo output or block the session until it does [default: drop on a terminal, block otherwise]"
+    )]
+    echo_overflow: Option<EchoOverflow>,
+    #[clap(
+        long,
+        help = "Convert CRLF line endings to LF before sending to the server"
+    )]
+    normalize_newlines: bool,
+    #[clap(
+        long,
+        help = "Input line that ends the input towards the server while still reading its replies, needs a server that supports it (best with --line-mode)"
+    )]
+    eof_marker: Option<String>,
+    #[clap(
+        long,
+        help = "Buffer the program output and send it to the server only in complete lines"
+    )]
+    line_mode: bool,
+    #[clap(
+        long,
+        help = "Stop the session when the same message bounces back and forth more than this many times within a few seconds"
+    )]
+    loop_limit: Option<u32>,
+    #[clap(
+        long,
+        help = "End the session if the server sends data that is not valid UTF-8"
+    )]
+    strict_utf8: bool,
+    #[clap(
+        long,
+        help = "TESTING ONLY: delay and drop forwarded messages, e.g. latency=50,jitter=20,drop=0.01,seed=42"
+    )]
+    chaos: Option<Chaos>,
+    #[clap(
+        long,
+        value_name = "REGEX",
+        help = "End the session with an error as soon as the server sends a line matching this pattern"
+    )]
+    fail_fast: Option<Regex>,
+    #[clap(
+        long,
+        help = "Wait this many milliseconds after connecting before sending the program output"
+    )]
+    post_connect_delay: Option<u64>,
+    #[clap(
+        long,
+        value_name = "MS",
+        help = "If the program crashes, end its input towards the server, keep the session open this many milliseconds and show what the server sent along with the crash"
+    )]
+    crash_grace: Option<u64>,
+    #[clap(
+        long,
+        value_name = "MS",
+        help = "Ping the server this often and end the session if it stops answering"
+    )]
+    ping_interval: Option<u64>,
+    #[clap(
+        long,
+        default_value = "3",
+        help = "Consecutive pings without a pong within --ping-interval after which the server is considered dead"
+    )]
+    ping_failures: u32,
+    #[clap(
+        long,
+        help = "Let SIGUSR1 pause and resume forwarding the program output, which waits in the pipe meanwhile (Unix only)"
+    )]
+    pausable: bool,
+    #[clap(
+        long,
+        requires = "pausable",
+        help = "Also hold back what the server sends while paused, pings keep flowing"
+    )]
+    pause_inbound: bool,
+    #[clap(
+        long,
+        value_name = "REGEX",
+        help = "Server lines acknowledging program messages, the first group being the message number"
+    )]
+    ack_pattern: Option<Regex>,
+    #[clap(
+        long,
+        default_value = "5000",
+        help = "Milliseconds after which an unacknowledged message is reported as possibly dropped"
+    )]
+    ack_timeout: u64,
+    #[clap(
+        long,
+        help = "Refuse program lines longer than this many bytes, the --outbound-* rules imply --line-mode"
+    )]
+    outbound_max_size: Option<usize>,
+    #[clap(long, help = "Refuse program lines not starting with this text")]
+    outbound_prefix: Option<String>,
+    #[clap(
+        long,
+        help = "Refuse program lines not ending with this text, newline included, \\n, \\r and \\t are unescaped"
+    )]
+    outbound_terminator: Option<String>,
+    #[clap(
+        arg_enum,
+        long,
+        help = "Refuse program lines with bytes outside this charset"
+    )]
+    outbound_charset: Option<Charset>,
+    #[clap(
+        long,
+        parse(try_from_str = parse_frame_marker),
+        value_name = "BYTES",
+        help = "Prepend these bytes to every message sent to the server, as escaped text or hex:HEX"
+    )]
+    frame_prefix: Option<Box<[u8]>>,
+    #[clap(
+        long,
+        parse(try_from_str = parse_frame_marker),
+        value_name = "BYTES",
+        help = "Append these bytes to every message sent to the server unless it already ends with them"
+    )]
+    frame_suffix: Option<Box<[u8]>>,
+    #[clap(
+        long,
+        help = "Remove the frame prefix and suffix from the messages of the server"
+    )]
+    strip_frames: bool,
+    #[clap(
+        long,
+        help = "Split data sent to the server in messages of at most this many bytes"
+    )]
+    max_frame_payload: Option<usize>,
+    #[clap(
+        long,
+        help = "Seal the session messages with ChaCha20-Poly1305 keyed by this file, on top of TLS, needs a server started with the same --e2e-key-file"
+    )]
+    e2e_key_file: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Fail if the server does not answer the first program message within this many milliseconds"
+    )]
+    max_first_response: Option<u64>,
+    #[clap(
+        long,
+        help = "Warn when no data is exchanged with the server for this many seconds",
+        parse(try_from_str = parse_seconds)
+    )]
+    idle_warning: Option<f64>,
+    #[clap(
+        long,
+        help = "Fail if the server sent fewer than this many bytes during the session"
+    )]
+    require_min_received: Option<u64>,
+    #[clap(
+        long,
+        parse(try_from_str = DateTime::parse_from_rfc3339),
+        help = "End the session at this RFC 3339 time (e.g. 2024-06-01T12:00:00Z)"
+    )]
+    deadline: Option<DateTime<FixedOffset>>,
+    #[clap(long, help = "Show verdicts sent by the service as a PASS/FAIL line")]
+    parse_verdict: bool,
+    #[clap(
+        long,
+        help = "JSON keys of the verdict result, score and message",
+        default_value = "result,score,message"
+    )]
+    verdict_keys: VerdictKeys,
+    #[clap(
+        arg_enum,
+        short = 'k',
+        long,
+        help = "Disable colored output",
+        default_value = "auto"
+    )]
+    color: Color,
+    #[clap(short = 'x', long, help = "Authentication token")]
+    auth_token: Option<String>,
+    #[clap(
+        arg_enum,
+        short,
+        long,
+        help = "Channel for program communication",
+        default_value = "stdio"
+    )]
+    channel: CommunicationChannel,
+    #[clap(
+        help = "Remote problem to connect to, or a ws[s]://host[/prefix]/problem/service?arg=value shorthand"
+    )]
+    problem: String,
+    #[clap(help = "Service wanted [default: solve]")]
+    service: Option<String>,
+    #[clap(short = 'a', long, multiple_occurrences(true), parse(try_from_str = parse_key_val_optional), help = "Service arguments, can be specified multiple times with -a arg=val, use -a seed=auto to get a random seed")]
+    service_arg: Vec<(String, String)>,
+    #[clap(
+        long,
+        help = "Load service arguments from a JSON or TOML file, -a takes precedence; RUST_LOG=debug shows where each one came from"
+    )]
+    args_file: Option<PathBuf>,
+    #[clap(short = 'f', long, multiple_occurrences(true), parse(try_from_str = parse_key_val), help = "File arguments, can be specified multiple times with -f arg=file")]
+    file_arg: Vec<(String, String)>,
+    #[clap(
+        short,
+        long,
+        help = "Path to output the attachments",
+        default_value = "./output/"
+    )]
+    output: PathBuf,
+    #[clap(
+        long,
+        help = "Relay SIGINT and SIGTERM to the local program instead of being killed by them (Unix only)"
+    )]
+    forward_signals: bool,
+    #[clap(
+        arg_enum,
+        long,
+        default_value = "kill",
+        help = "How to stop the local program when the session ends abnormally"
+    )]
+    on_session_end: SessionEnd,
+    #[clap(
+        long,
+        allow_hyphen_values = true,
+        parse(try_from_str = parse_nice),
+        help = "Run the program with this niceness, from -20 to 19 (negative values need privileges); on Windows positive values map to the below normal or idle priority class and negative ones to above normal"
+    )]
+    nice: Option<i32>,
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        help = "Limit the CPU time of the program, which then gets SIGXCPU (Unix only)"
+    )]
+    limit_cpu: Option<u64>,
+    #[clap(
+        long,
+        value_name = "MIB",
+        parse(try_from_str = parse_mebibytes),
+        help = "Limit the address space of the program, making larger allocations fail (Unix only)"
+    )]
+    limit_mem: Option<u64>,
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Limit the number of files the program can keep open (Unix only)"
+    )]
+    limit_files: Option<u64>,
+    #[clap(
+        long,
+        help = "Write every message with its sequence number, direction and time to this file"
+    )]
+    debug_seq: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "SECS",
+        requires = "debug-seq",
+        help = "Write the --debug-seq file out this often during the session, not only at its end"
+    )]
+    debug_seq_flush: Option<f64>,
+    #[clap(
+        long,
+        help = "Shell command that reads the session transcript on stdin and decides the result"
+    )]
+    scorer: Option<String>,
+    #[clap(
+        long,
+        value_name = "URL|unix:PATH|HOST:PORT",
+        help = "Send a read-only copy of every session message to an observer as JSON"
+    )]
+    mirror_to: Option<String>,
+    #[clap(
+        long,
+        help = "After the session, run it again every time this file is modified"
+    )]
+    watch: Option<PathBuf>,
+    #[clap(
+        long = "stats",
+        help = "Print message statistics at the end of the session"
+    )]
+    print_stats: bool,
+    #[clap(
+        long,
+        conflicts_with_all = &["program", "channel"],
+        help = "Forward stdin to the server but discard what it sends, then print only the statistics"
+    )]
+    summary_only: bool,
+    #[clap(help = "Local executable and executable arguments", raw = true)]
+    program: Vec<String>,
+}
+
+impl Command {
+    async fn run<T: Sink<Message> + Unpin, U: Stream<Item = Result<Message, TsError>> + Unpin>(
+        self,
+        wsout: &mut T,
+        wsin: &mut U,
+        ask_to_exit: &mut bool,
+        stats: &mut SessionStats,
+    ) -> Result<(), RtalError>
+    where
+        <T as Sink<Message>>::Error: Display,
+    {
+        match self {
+            Command::List {
+                filter,
+                mut verbose,
+            } => {
+                let request = Request::MetaList {};
+                let reply = match oneshot_request(request, wsout, wsin).await? {
+                    Reply::MetaList { meta } => meta,
+                    _ => {
+                        return Err(RtalError::Server(
+                            "Server sent an invalid response".to_string(),
+                        ))
+                    }
+                };
+                verbose += if filter.is_some() { 1 } else { 0 };
+                match print_problems(
+                    &mut std::io::stdout().lock(),
+                    reply,
+                    filter.as_ref(),
+                    verbose,
+                ) {
+                    Ok(()) => Ok(()),
+                    // The reader of a pipe has seen enough, like `rtal list | head`
+                    Err(x) if x.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+                    Err(x) => Err(RtalError::Io(format!("Cannot print the problems: {}", x))),
+                }
+            }
+            Command::Get {
+                problems,
+                output,
+                base_dir,
+                no_compress,
+            } => {
+                let template =
+                    match output {
+                        Some(ref x) if x.contains("{problem}") || x.contains("{name}") => Some(x),
+                        Some(_) if problems.len() > 1 => return Err(RtalError::Usage(
+                            "--output needs a {problem} or {name} placeholder when downloading \
+                             more than one problem"
+                                .to_string(),
+                        )),
+                        Some(_) => None,
+                        None => None,
+                    };
+                for problem in problems {
+                    let request = Request::Attachment {
+                        problem: problem.clone(),
+                        compress: !no_compress,
+                    };
+                    match oneshot_request(request, wsout, wsin).await? {
+                        Reply::Attachment { status: Ok(()) } => {}
+                        Reply::Attachment { status: Err(x) } => {
+                            return Err(RtalError::Server(format!(
+                                "Cannot download attachment: {}",
+                                x
+                            )))
+                        }
+                        _ => {
+                            return Err(RtalError::Server(
+                                "Server sent an invalid response".to_string(),
+                            ))
+                        }
+                    };
+                    let path = |name: &str| match (template, &output) {
+                        (Some(x), _) => expand_output(x, &base_dir, &problem, name),
+                        (None, Some(x)) => Ok(PathBuf::from(x)),
+                        (None, None) => expand_output("{name}", &base_dir, &problem, name),
+                    };
+                    match recv_binary_file(wsin, path).await {
+                        Ok(x) => debug!("Downloaded {:?}", x),
+                        Err(x) => {
+                            return Err(download_error("Error while downloading the attachment", x))
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Command::Connect(connect) => {
+                let ConnectArgs {
+                    echo,
+                    echo_wrap,
+                    echo_filter,
+                    echo_strip_ansi,
+                    echo_overflow,
+                    normalize_newlines,
+                    eof_marker,
+                    line_mode,
+                    loop_limit,
+                    strict_utf8,
+                    chaos,
+                    fail_fast,
+                    post_connect_delay,
+                    crash_grace,
+                    debug_seq_flush,
+                    ping_interval,
+                    ping_failures,
+                    pausable,
+                    pause_inbound,
+                    ack_pattern,
+                    ack_timeout,
+                    outbound_max_size,
+                    outbound_prefix,
+                    outbound_terminator,
+                    outbound_charset,
+                    frame_prefix,
+                    frame_suffix,
+                    strip_frames,
+                    max_frame_payload,
+                    e2e_key_file,
+                    max_first_response,
+                    idle_warning,
+                    require_min_received,
+                    deadline,
+                    parse_verdict,
+                    verdict_keys,
+                    color,
+                    auth_token,
+                    channel,
+                    problem,
+                    service,
+                    service_arg,
+                    args_file,
+                    file_arg,
+                    output,
+                    forward_signals,
+                    on_session_end,
+                    nice,
+                    limit_cpu,
+                    limit_mem,
+                    limit_files,
+                    debug_seq,
+                    scorer,
+                    mirror_to,
+                    watch: _,
+                    print_stats,
+                    summary_only,
+                    program,
+                } = *connect;
+                let deadline = match deadline {
+                    Some(x) => Some(deadline_instant(&x)?),
+                    None => None,
+                };
+                let mut args = match args_file {
+                    Some(path) => load_args_file(&path).await?,
+                    None => Vec::new(),
+                };
+                for (name, value) in service_arg {
+                    debug!("Argument {} from the command line", name);
+                    args.push((name, value));
+                }
+                let mut service_arg = args;
+                for (name, value) in service_arg.iter_mut() {
+                    if name == "seed" && value == AUTO_SEED {
+                        // TALight services conventionally accept six digits seeds
+                        let seed: u32 = rand::thread_rng().gen_range(100000..=999999);
+                        *value = seed.to_string();
+                        println!("> Reproduce this run with -a seed={}", seed);
+                    }
+                }
+                let request = Request::ConnectBegin {
+                    problem,
+                    service: service.unwrap_or_else(|| DEFAULT_SERVICE.to_string()),
+                    args: service_arg.into_iter().collect(),
+                    tty: match color {
+                        Color::Auto => program.len() == 0 && channel == CommunicationChannel::Stdio,
+                        Color::Off => false,
+                        Color::On => true,
+                    },
+                    token: auth_token,
+                    files: file_arg.iter().map(|x| x.0.clone()).collect(),
+                };
+                let allowed_files = match oneshot_request(request, wsout, wsin).await? {
+                    Reply::ConnectBegin { status: Ok(x) } => x,
+                    Reply::ConnectBegin { status: Err(x) } => {
+                        return Err(RtalError::Server(format!("Cannot connect: {}", x)))
+                    }
+                    _ => {
+                        return Err(RtalError::Server(
+                            "Server sent an invalid response".to_string(),
+                        ))
+                    }
+                };
+                let provided_files: HashMap<_, _> = file_arg.into_iter().collect();
+                let allowed_files: HashSet<_> = allowed_files.into_iter().collect();
+                let mut to_upload = Vec::new();
+                for (name, path) in provided_files {
+                    if !allowed_files.contains(&name) {
+                        return Err(RtalError::Usage(format!(
+                            "File \"{}\" is not allowed",
+                            &name
+                        )));
+                    }
+                    match tokio::fs::read(&path).await {
+                        Ok(x) => to_upload.push((name, x)),
+                        Err(x) => {
+                            return Err(RtalError::Io(format!("Cannot read {}: {}", path, x)))
+                        }
+                    }
+                }
+                for (name, data) in to_upload {
+                    if let Err(x) = send_binary_data(wsout, &name, &data).await {
+                        return Err(RtalError::PeerClosed(format!(
+                            "Cannot send input file: {}",
+                            x
+                        )));
+                    }
+                }
+                match oneshot_reply(wsin).await? {
+                    Reply::ConnectStart { status: Ok(()) } => {}
+                    Reply::ConnectStart { status: Err(x) } => {
+                        return Err(RtalError::Server(format!("Cannot start connection: {}", x)))
+                    }
+                    _ => {
+                        return Err(RtalError::Server(
+                            "Server sent an invalid response".to_string(),
+                        ))
+                    }
+                };
+                let mut options = SessionOptions {
+                    echo,
+                    colored: color.enabled(),
+                    verdict: if parse_verdict {
+                        Some(verdict_keys)
+                    } else {
+                        None
+                    },
+                    normalize_newlines,
+                    eof_marker,
+                    max_frame_payload,
+                    max_first_response,
+                    echo_wrap,
+                    echo_filter,
+                    echo_strip_ansi,
+                    // Only an interactive session favours the protocol over a complete echo
+                    echo_overflow: echo_overflow.unwrap_or_else(|| {
+                        if std::io::stdout().is_terminal() {
+                            EchoOverflow::Drop
+                        } else {
+                            EchoOverflow::Block
+                        }
+                    }),
+                    idle_warning,
+                    deadline,
+                    line_mode,
+                    loop_limit,
+                    strict_utf8,
+                    chaos,
+                    fail_fast,
+                    post_connect_delay,
+                    crash_grace,
+                    debug_seq_flush,
+                    ping_interval,
+                    ping_failures,
+                    pausable,
+                    pause_inbound,
+                    outbound: OutboundRules {
+                        max_size: outbound_max_size,
+                        prefix: outbound_prefix,
+                        terminator: outbound_terminator.map(|x| {
+                            x.replace("\\n", "\n")
+                                .replace("\\r", "\r")
+                                .replace("\\t", "\t")
+                        }),
+                        charset: outbound_charset,
+                    },
+                    framing: Framing {
+                        prefix: frame_prefix.map(Vec::from).unwrap_or_default(),
+                        suffix: frame_suffix.map(Vec::from).unwrap_or_default(),
+                        strip_inbound: strip_frames,
+                    },
+                    e2e: match e2e_key_file {
+                        Some(ref x) => {
+                            Some(Arc::new(E2eKey::load(x).await.map_err(RtalError::Io)?))
+                        }
+                        None => None,
+                    },
+                };
+                if let Some(ref mut x) = options.chaos {
+                    let seed = *x.seed.get_or_insert_with(|| rand::thread_rng().gen());
+                    eprintln!(
+                        "> Chaos testing is on, reproduce this run with seed={}",
+                        seed
+                    );
+                }
+                let program_options = ProgramOptions {
+                    forward_signals,
+                    on_session_end,
+                    nice,
+                    limits: Limits {
+                        cpu: limit_cpu,
+                        memory: limit_mem,
+                        files: limit_files,
+                    },
+                };
+                stats.acks =
+                    ack_pattern.map(|x| AckTracker::new(x, Duration::from_millis(ack_timeout)));
+                let mut log = SessionLog {
+                    debug_seq: match debug_seq {
+                        Some(path) => match tokio::fs::File::create(&path).await {
+                            Ok(x) => Some(BufWriter::new(x)),
+                            Err(x) => {
+                                return Err(RtalError::Io(format!(
+                                    "Cannot create {:?}: {}",
+                                    path, x
+                                )))
+                            }
+                        },
+                        None => None,
+                    },
+                    transcript: scorer.as_ref().map(|_| Vec::new()),
+                    mirror: match mirror_to {
+                        Some(ref x) => Some(open_mirror(x).await?),
+                        None => None,
+                    },
+                    mirror_dropped: 0,
+                };
+                // BEGIN: Handle channels
+                let output_files = match channel {
+                    CommunicationChannel::Stdio => {
+                        if program.len() > 0 {
+                            let mut prog = program_command(&program);
+                            prog.stdout(Stdio::piped());
+                            prog.stdin(Stdio::piped());
+                            let mut prog = Program::spawn(prog, &program_options, false)?;
                             let result = handle_connection(
                                 wsout,
                                 wsin,
-                                prog.stdout.take().expect("Cannot fail"),
-                                prog.stdin.take().expect("Cannot fail"),
-                                echo,
+                                prog.child.stdout.take().expect("Cannot fail"),
+                                prog.child.stdin.take().expect("Cannot fail"),
+                                Some(&mut prog.child),
+                                &options,
+                                stats,
+                                &mut log,
+                            )
+                            .await;
+                            prog.finish(result).await
+                        } else if summary_only {
+                            let options = SessionOptions {
+                                echo: false,
+                                verdict: None,
+                                ..options.clone()
+                            };
+                            let result = handle_connection(
+                                wsout,
+                                wsin,
+                                stdin(),
+                                tokio::io::sink(),
+                                None,
+                                &options,
+                                stats,
+                                &mut log,
                             )
                             .await;
-                            match result {
-                                Ok(y) => match prog.wait().await {
-                                    Ok(x) if x.success() => Ok(y),
-                                    Ok(x) => {
-                                        warn!("Program exited with non-zero code: {}", x);
-                                        Ok(y)
-                                    }
-                                    Err(x) => {
-                                        warn!("Program exited abruptly: {}", x);
-                                        Ok(y)
-                                    }
-                                },
-                                Err(x) => {
-                                    drop(prog.kill().await);
-                                    Err(x)
-                                }
-                            }
-                        } else {
-                            let result =
-                                handle_connection(wsout, wsin, stdin(), stdout(), false).await;
                             *ask_to_exit = true;
                             result
-                        }
+                        } else {
+                            let options = SessionOptions {
+                                echo: false,
+                                ..options.clone()
+                            };
+                            let result = handle_connection(
+                                wsout,
+                                wsin,
+                                stdin(),
+                                stdout(),
+                                None,
+                                &options,
+                                stats,
+                                &mut log,
+                            )
+                            .await;
+                            *ask_to_exit = true;
+                            result
+                        }
                     }
                     #[cfg(unix)]
                     CommunicationChannel::Pipe => {
                         let pid = Pid::this();
-                        let dir = tempdir().map_err(|x| format!("Cannot create tempdir: {}", x))?;
+                        let dir = tempdir()
+                            .map_err(|x| RtalError::Io(format!("Cannot create tempdir: {}", x)))?;
                         let inpipe_name = dir.path().join(format!("rtal.{}.out", pid));
                         let inp = inpipe_name.to_string_lossy();
                         let outpipe_name = dir.path().join(format!("rtal.{}.in", pid));
                         let outp = outpipe_name.to_string_lossy();
                         mkfifo(&inpipe_name, Mode::S_IRWXU)
-                            .map_err(|x| format!("Cannot create pipe: {}", x))?;
+                            .map_err(|x| RtalError::Io(format!("Cannot create pipe: {}", x)))?;
                         mkfifo(&outpipe_name, Mode::S_IRWXU)
-                            .map_err(|x| format!("Cannot create pipe: {}", x))?;
+                            .map_err(|x| RtalError::Io(format!("Cannot create pipe: {}", x)))?;
                         if program.len() > 0 {
-                            let mut prog = proc::Command::new(&program[0]);
-                            if program.len() > 1 {
-                                prog.args(&program[1..]);
-                            }
+                            let mut prog = program_command(&program);
                             prog.env("RTAL_PIPEIN", &outpipe_name);
                             prog.env("RTAL_PIPEOUT", &inpipe_name);
-                            let mut prog = match prog.spawn() {
-                                Ok(x) => x,
-                                Err(x) => return Err(format!("Cannot spawn program: {}", x)),
-                            };
+                            let mut prog = Program::spawn(prog, &program_options, true)?;
                             println!("> Waiting until the program opens the pipes");
                             let result = {
                                 let mut inpipe = OpenOptions::new();
@@ -374,28 +1442,32 @@ impl Command {
                                 );
                                 let (inpipe, outpipe) = match (inpipe, outpipe) {
                                     (Ok(x), Ok(y)) => (x, y),
-                                    (Err(x), _) => return Err(format!("Cannot open pipe: {}", x)),
-                                    (_, Err(y)) => return Err(format!("Cannot open pipe: {}", y)),
-                                };
-                                handle_connection(wsout, wsin, inpipe, outpipe, echo).await
-                            };
-                            match result {
-                                Ok(y) => match prog.wait().await {
-                                    Ok(x) if x.success() => Ok(y),
-                                    Ok(x) => {
-                                        warn!("Program exited with non-zero code: {}", x);
-                                        Ok(y)
+                                    (Err(x), _) => {
+                                        return Err(RtalError::Io(format!(
+                                            "Cannot open pipe: {}",
+                                            x
+                                        )))
                                     }
-                                    Err(x) => {
-                                        warn!("Program exited abruptly: {}", x);
-                                        Ok(y)
+                                    (_, Err(y)) => {
+                                        return Err(RtalError::Io(format!(
+                                            "Cannot open pipe: {}",
+                                            y
+                                        )))
                                     }
-                                },
-                                Err(x) => {
-                                    drop(prog.kill().await);
-                                    Err(x)
-                                }
-                            }
+                                };
+                                handle_connection(
+                                    wsout,
+                                    wsin,
+                                    inpipe,
+                                    outpipe,
+                                    Some(&mut prog.child),
+                                    &options,
+                                    stats,
+                                    &mut log,
+                                )
+                                .await
+                            };
+                            prog.finish(result).await
                         } else {
                             println!("> Input (stdin-like) pipe: {}", outp);
                             println!("> Output (stdout-like) pipe: {}", inp);
@@ -408,275 +1480,3154 @@ impl Command {
                             );
                             let (inpipe, outpipe) = match (inpipe, outpipe) {
                                 (Ok(x), Ok(y)) => (x, y),
-                                (Err(x), _) => return Err(format!("Cannot open pipe: {}", x)),
-                                (_, Err(y)) => return Err(format!("Cannot open pipe: {}", y)),
+                                (Err(x), _) => {
+                                    return Err(RtalError::Io(format!("Cannot open pipe: {}", x)))
+                                }
+                                (_, Err(y)) => {
+                                    return Err(RtalError::Io(format!("Cannot open pipe: {}", y)))
+                                }
                             };
-                            handle_connection(wsout, wsin, inpipe, outpipe, echo).await
+                            handle_connection(
+                                wsout, wsin, inpipe, outpipe, None, &options, stats, &mut log,
+                            )
+                            .await
                         }
                     }
-                };
-                // END: Handle channels
-                let output_files = match output_files {
-                    Ok(x) => x,
-                    Err(x) => return Err(x),
-                };
-                let mut to_write = Vec::new();
-                for _ in 0..output_files.len() {
-                    let (name, data) = recv_binary_data(wsin).await?;
-                    to_write.push((name, data));
+                };
+                // END: Handle channels
+                log.finish().await;
+                if let Some(ref mut acks) = stats.acks {
+                    acks.report();
+                }
+                if print_stats || summary_only {
+                    stats.print();
+                    if let Some(seed) = options.chaos.as_ref().and_then(|x| x.seed) {
+                        println!("> Chaos seed {}", seed);
+                    }
+                }
+                let output_files = match output_files {
+                    Ok(x) => x,
+                    Err(x) => return Err(x),
+                };
+                if let Some(min) = require_min_received {
+                    if stats.received.bytes < min {
+                        return Err(RtalError::TooLittleReceived(format!(
+                            "The server sent only {} bytes, at least {} were required",
+                            stats.received.bytes, min
+                        )));
+                    }
+                }
+                if !output_files.is_empty() {
+                    tokio::fs::create_dir_all(&output).await.map_err(|x| {
+                        RtalError::Io(format!("Cannot create {:?}: {}", &output, x))
+                    })?;
+                }
+                for _ in 0..output_files.len() {
+                    let filename = recv_binary_file(wsin, |name: &str| Ok(output.join(name)))
+                        .await
+                        .map_err(|x| download_error("Error while receiving an output file", x))?;
+                    println!("Received {:?}", &filename);
+                }
+                if let (Some(command), Some(transcript)) = (scorer, log.transcript) {
+                    run_scorer(&command, transcript).await?;
+                }
+                Ok(())
+            }
+            Command::Probe { pings, frame_size } => {
+                let mut rtts = Vec::new();
+                for i in 0..pings {
+                    rtts.push(probe_rtt(wsout, wsin, i.to_be_bytes().to_vec()).await?);
+                }
+                if let (Some(min), Some(max)) = (rtts.iter().min(), rtts.iter().max()) {
+                    let avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+                    println!(
+                        "> Round-trip latency: min {:.1} ms, avg {:.1} ms, max {:.1} ms",
+                        min.as_secs_f64() * 1000.0,
+                        avg.as_secs_f64() * 1000.0,
+                        max.as_secs_f64() * 1000.0
+                    );
+                }
+                let rtt = rtts.iter().min().cloned().unwrap_or_default();
+                let largest = frame_size
+                    .checked_mul(1024)
+                    .map_or(PROBE_MAX_FRAME_SIZE, |x| x.min(PROBE_MAX_FRAME_SIZE));
+                let mut size = PROBE_MIN_FRAME_SIZE.min(largest);
+                while size > 0 {
+                    let start = Instant::now();
+                    // The server ignores binary messages outside of a connection,
+                    // the pong tells when the whole frame went through
+                    if let Err(x) = wsout.send(Message::Binary(vec![0; size])).await {
+                        println!("> Frames of {} KiB: failed ({})", size >> 10, x);
+                        break;
+                    }
+                    if let Err(x) = probe_rtt(wsout, wsin, Vec::new()).await {
+                        println!("> Frames of {} KiB: failed ({})", size >> 10, x);
+                        break;
+                    }
+                    let transfer = start.elapsed().saturating_sub(rtt).as_secs_f64().max(1e-6);
+                    println!(
+                        "> Frames of {} KiB: ok, {:.2} MiB/s",
+                        size >> 10,
+                        size as f64 / transfer / (1 << 20) as f64
+                    );
+                    size = if size < largest {
+                        (size * 4).min(largest)
+                    } else {
+                        0
+                    };
+                }
+                Ok(())
+            }
+            Command::Bench {
+                problem,
+                service,
+                payload_size,
+                duration,
+                concurrency,
+            } => {
+                let request = Request::ConnectBegin {
+                    problem,
+                    service,
+                    args: HashMap::new(),
+                    tty: false,
+                    token: None,
+                    files: Vec::new(),
+                };
+                match oneshot_request(request, wsout, wsin).await? {
+                    Reply::ConnectBegin { status: Ok(_) } => {}
+                    Reply::ConnectBegin { status: Err(x) } => {
+                        return Err(RtalError::Server(format!("Cannot connect: {}", x)))
+                    }
+                    _ => {
+                        return Err(RtalError::Server(
+                            "Server sent an invalid response".to_string(),
+                        ))
+                    }
+                };
+                match oneshot_reply(wsin).await? {
+                    Reply::ConnectStart { status: Ok(()) } => {}
+                    Reply::ConnectStart { status: Err(x) } => {
+                        return Err(RtalError::Server(format!("Cannot start connection: {}", x)))
+                    }
+                    _ => {
+                        return Err(RtalError::Server(
+                            "Server sent an invalid response".to_string(),
+                        ))
+                    }
+                };
+                let report = bench(
+                    wsout,
+                    wsin,
+                    payload_size.max(1),
+                    Duration::from_secs_f64(duration),
+                    concurrency.max(1),
+                )
+                .await?;
+                match oneshot_request(Request::ConnectStop {}, wsout, wsin).await? {
+                    Reply::ConnectStop { status: Ok(_) } => {}
+                    Reply::ConnectStop { status: Err(x) } => return Err(RtalError::Server(x)),
+                    _ => {
+                        return Err(RtalError::Server(
+                            "Server sent an invalid response".to_string(),
+                        ))
+                    }
+                }
+                report.print();
+                Ok(())
+            }
+            Command::Relay { listen, log } => {
+                let mut log = SessionLog {
+                    debug_seq: match log {
+                        Some(path) => match tokio::fs::File::create(&path).await {
+                            Ok(x) => Some(BufWriter::new(x)),
+                            Err(x) => {
+                                return Err(RtalError::Io(format!(
+                                    "Cannot create {:?}: {}",
+                                    path, x
+                                )))
+                            }
+                        },
+                        None => None,
+                    },
+                    transcript: None,
+                    mirror: None,
+                    mirror_dropped: 0,
+                };
+                let client = accept_client(&listen).await?;
+                let result = relay(client, wsout, wsin, &mut log, stats).await;
+                log.finish().await;
+                result
+            }
+        }
+    }
+}
+
+/// Figures of a benchmark run, printed as `bench.KEY=VALUE` lines for tools tracking them.
+struct BenchReport {
+    messages: usize,
+    bytes: u64,
+    elapsed: Duration,
+    latencies: Vec<Duration>,
+}
+
+impl BenchReport {
+    fn print(&self) {
+        let seconds = self.elapsed.as_secs_f64().max(1e-6);
+        println!("bench.messages={}", self.messages);
+        println!("bench.bytes={}", self.bytes);
+        println!("bench.seconds={:.3}", seconds);
+        println!(
+            "bench.messages_per_second={:.1}",
+            self.messages as f64 / seconds
+        );
+        println!("bench.bytes_per_second={:.0}", self.bytes as f64 / seconds);
+        let mut latencies = self.latencies.clone();
+        latencies.sort();
+        for (name, quantile) in [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("max", 1.0)] {
+            let index = ((latencies.len() as f64 * quantile).ceil() as usize).max(1) - 1;
+            if let Some(x) = latencies.get(index) {
+                println!("bench.latency_ms.{}={:.3}", name, x.as_secs_f64() * 1000.0);
+            }
+        }
+    }
+}
+
+/// Keeps `concurrency` messages in flight for `duration`, then waits for the last echoes. The
+/// echo may be split or merged arbitrarily, so messages are matched by cumulative byte count.
+async fn bench<T: Sink<Message> + Unpin, U: Stream<Item = Result<Message, TsError>> + Unpin>(
+    wsout: &mut T,
+    wsin: &mut U,
+    payload_size: usize,
+    duration: Duration,
+    concurrency: usize,
+) -> Result<BenchReport, RtalError>
+where
+    <T as Sink<Message>>::Error: Display,
+{
+    let mut payload = vec![b'x'; payload_size];
+    payload[payload_size - 1] = b'\n';
+    let start = Instant::now();
+    let mut in_flight = VecDeque::new();
+    let mut sent = 0u64;
+    let mut received = 0u64;
+    let mut latencies = Vec::new();
+    loop {
+        while start.elapsed() < duration && in_flight.len() < concurrency {
+            if let Err(x) = wsout.send(Message::Binary(payload.clone())).await {
+                return Err(RtalError::PeerClosed(format!(
+                    "Cannot send data to server: {}",
+                    x
+                )));
+            }
+            sent += payload_size as u64;
+            in_flight.push_back((sent, Instant::now()));
+        }
+        if in_flight.is_empty() {
+            break;
+        }
+        match timeout(Duration::from_secs_f64(PROBE_TIMEOUT), wsin.next()).await {
+            Ok(Some(Ok(Message::Binary(x)))) => received += x.len() as u64,
+            Ok(Some(Ok(Message::Text(x)))) => {
+                return Err(RtalError::Server(format!(
+                    "Service ended the benchmark: {}",
+                    x
+                )))
+            }
+            Ok(Some(Ok(_))) => continue,
+            Ok(Some(Err(x))) => {
+                return Err(RtalError::PeerClosed(format!("Connection lost: {}", x)))
+            }
+            Ok(None) => return Err(RtalError::PeerClosed("Connection lost".to_string())),
+            Err(_) => {
+                return Err(RtalError::Timeout(
+                    "The service stopped echoing".to_string(),
+                ))
+            }
+        }
+        while let Some(&(end, sent_at)) = in_flight.front() {
+            if received < end {
+                break;
+            }
+            latencies.push(sent_at.elapsed());
+            in_flight.pop_front();
+        }
+    }
+    Ok(BenchReport {
+        messages: latencies.len(),
+        bytes: received,
+        elapsed: start.elapsed(),
+        latencies,
+    })
+}
+
+/// Expands an `rtal get` output template, refusing paths that would leave `base`.
+fn expand_output(
+    template: &str,
+    base: &Path,
+    problem: &str,
+    name: &str,
+) -> Result<PathBuf, String> {
+    let path = PathBuf::from(
+        template
+            .replace("{problem}", problem)
+            .replace("{name}", name),
+    );
+    if path
+        .components()
+        .any(|x| !matches!(x, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(format!("{:?} would be outside of {:?}", path, base));
+    }
+    Ok(base.join(path))
+}
+
+async fn probe_rtt<T: Sink<Message> + Unpin, U: Stream<Item = Result<Message, TsError>> + Unpin>(
+    wsout: &mut T,
+    wsin: &mut U,
+    payload: Vec<u8>,
+) -> Result<Duration, RtalError>
+where
+    <T as Sink<Message>>::Error: Display,
+{
+    let start = Instant::now();
+    if let Err(x) = wsout.send(Message::Ping(payload.clone())).await {
+        return Err(RtalError::PeerClosed(format!("Cannot send ping: {}", x)));
+    }
+    loop {
+        match timeout(Duration::from_secs_f64(PROBE_TIMEOUT), wsin.next()).await {
+            Ok(Some(Ok(Message::Pong(x)))) if x == payload => break Ok(start.elapsed()),
+            Ok(Some(Ok(_))) => continue,
+            Ok(Some(Err(x))) => {
+                break Err(RtalError::PeerClosed(format!("Connection lost: {}", x)))
+            }
+            Ok(None) => break Err(RtalError::PeerClosed("Connection lost".to_string())),
+            Err(_) => {
+                break Err(RtalError::Timeout(
+                    "No pong received from server".to_string(),
+                ))
+            }
+        }
+    }
+}
+
+const HISTOGRAM_BUCKETS: [(usize, &str); 4] = [
+    (64, "<64B"),
+    (1 << 10, "<1KiB"),
+    (64 << 10, "<64KiB"),
+    (1 << 20, "<1MiB"),
+];
+
+#[derive(Debug, Default)]
+struct DirectionStats {
+    messages: u64,
+    bytes: u64,
+    histogram: [u64; HISTOGRAM_BUCKETS.len() + 1],
+}
+
+impl DirectionStats {
+    fn record(&mut self, size: usize) {
+        self.messages += 1;
+        self.bytes += size as u64;
+        let bucket = HISTOGRAM_BUCKETS
+            .iter()
+            .position(|(limit, _)| size < *limit)
+            .unwrap_or(HISTOGRAM_BUCKETS.len());
+        self.histogram[bucket] += 1;
+    }
+
+    fn print(&self, direction: &str) {
+        println!(
+            "> {}: {} messages, {} bytes",
+            direction, self.messages, self.bytes
+        );
+        let buckets: Vec<_> = HISTOGRAM_BUCKETS
+            .iter()
+            .map(|(_, name)| *name)
+            .chain([">=1MiB"])
+            .zip(self.histogram.iter())
+            .map(|(name, count)| format!("{}: {}", name, count))
+            .collect();
+        println!(">   {}", buckets.join(", "));
+    }
+}
+
+#[derive(Debug, Default)]
+struct SessionStats {
+    sent: DirectionStats,
+    received: DirectionStats,
+    first_response: Option<Duration>,
+    last_rtt: Option<Duration>,
+    acks: Option<AckTracker>,
+}
+
+/// Matches program messages with the server lines acknowledging them. The first capture group
+/// of the pattern holds the acknowledged message number, counting from 1.
+#[derive(Debug)]
+struct AckTracker {
+    pattern: Regex,
+    timeout: Duration,
+    pending: BTreeMap<u64, Instant>,
+    late: Vec<(u64, Duration)>,
+    // Server output after the last newline, an ack line may continue in the next message
+    partial: Vec<u8>,
+}
+
+impl AckTracker {
+    fn new(pattern: Regex, timeout: Duration) -> AckTracker {
+        AckTracker {
+            pattern,
+            timeout,
+            pending: BTreeMap::new(),
+            late: Vec::new(),
+            partial: Vec::new(),
+        }
+    }
+
+    fn sent(&mut self, seq: u64) {
+        self.pending.insert(seq, Instant::now());
+    }
+
+    fn received(&mut self, data: &[u8]) {
+        self.partial.extend_from_slice(data);
+        let lines: Vec<u8> = match self.partial.iter().rposition(|&x| x == b'\n') {
+            Some(x) => self.partial.drain(..=x).collect(),
+            None => return,
+        };
+        self.acknowledge(&lines);
+    }
+
+    fn acknowledge(&mut self, data: &[u8]) {
+        let text = String::from_utf8_lossy(data);
+        for line in text.lines() {
+            let seq = match self.pattern.captures(line).and_then(|x| x.get(1)) {
+                Some(x) => x.as_str().parse::<u64>(),
+                None => continue,
+            };
+            if let Some(sent) = seq
+                .ok()
+                .and_then(|x| self.pending.remove(&x).map(|y| (x, y)))
+            {
+                if sent.1.elapsed() > self.timeout {
+                    self.late.push((sent.0, sent.1.elapsed()));
+                }
+            }
+        }
+    }
+
+    fn report(&mut self) {
+        // The server may end the session without a newline after the last ack
+        let partial = std::mem::take(&mut self.partial);
+        self.acknowledge(&partial);
+        for (seq, delay) in &self.late {
+            warn!(
+                "Message {} was acknowledged only after {:.1} s",
+                seq,
+                delay.as_secs_f64()
+            );
+        }
+        let dropped: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(_, x)| x.elapsed() > self.timeout)
+            .map(|(x, _)| x.to_string())
+            .collect();
+        if !dropped.is_empty() {
+            warn!(
+                "Possibly dropped, never acknowledged: messages {}",
+                dropped.join(", ")
+            );
+        }
+    }
+}
+
+impl SessionStats {
+    fn print(&self) {
+        self.sent.print("Sent");
+        self.received.print("Received");
+        if let Some(x) = self.first_response {
+            println!("> First response after {:.1} ms", x.as_secs_f64() * 1000.0);
+        }
+        if let Some(x) = self.last_rtt {
+            println!("> Last ping round trip {:.1} ms", x.as_secs_f64() * 1000.0);
+        }
+    }
+}
+
+/// Notices the same payload being sent back as soon as it is received, in either direction.
+struct LoopWatchdog {
+    limit: u32,
+    last_sent: Option<u64>,
+    last_received: Option<u64>,
+    bounces: u32,
+    window_start: Instant,
+}
+
+impl LoopWatchdog {
+    fn new(limit: u32) -> LoopWatchdog {
+        LoopWatchdog {
+            limit,
+            last_sent: None,
+            last_received: None,
+            bounces: 0,
+            window_start: Instant::now(),
+        }
+    }
+
+    /// Records a message and tells whether the bounces exceeded the limit within the window.
+    fn bounced(&mut self, data: &[u8], sent: bool) -> bool {
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        let hash = hasher.finish();
+        let other = if sent {
+            self.last_received
+        } else {
+            self.last_sent
+        };
+        if other == Some(hash) {
+            if self.bounces == 0
+                || self.window_start.elapsed() > Duration::from_secs_f64(LOOP_WINDOW)
+            {
+                self.bounces = 0;
+                self.window_start = Instant::now();
+            }
+            self.bounces += 1;
+        } else {
+            self.bounces = 0;
+        }
+        if sent {
+            self.last_sent = Some(hash);
+        } else {
+            self.last_received = Some(hash);
+        }
+        self.bounces > self.limit
+    }
+
+    fn error(&self) -> RtalError {
+        RtalError::LoopDetected(format!(
+            "The same message bounced between server and program {} times in {:.1} seconds, stopping the session",
+            self.bounces,
+            self.window_start.elapsed().as_secs_f64()
+        ))
+    }
+}
+
+/// Figures about one run of the client, written out by --metrics-file.
+#[derive(Debug, Default)]
+struct Metrics {
+    server: Option<String>,
+    connect: Option<Duration>,
+    stats: SessionStats,
+}
+
+impl Metrics {
+    /// Renders the metrics in the Prometheus text exposition format.
+    fn render(&self, problem: &str, service: &str, success: bool) -> String {
+        let escape = |x: &str| {
+            x.replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+        };
+        let labels = format!(
+            "server=\"{}\",problem=\"{}\",service=\"{}\"",
+            escape(self.server.as_deref().unwrap_or_default()),
+            escape(problem),
+            escape(service)
+        );
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, labels: &str, value: String| {
+            out.push_str(&format!("# HELP {} {}\n", name, help));
+            out.push_str(&format!("# TYPE {} {}\n", name, kind));
+            out.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+        };
+        if let Some(x) = self.connect {
+            metric(
+                "rtal_connect_seconds",
+                "gauge",
+                "Time to open the WebSocket connection",
+                &labels,
+                x.as_secs_f64().to_string(),
+            );
+        }
+        if let Some(x) = self.stats.first_response {
+            metric(
+                "rtal_first_response_seconds",
+                "gauge",
+                "Time from the session start to the first server message",
+                &labels,
+                x.as_secs_f64().to_string(),
+            );
+        }
+        metric(
+            "rtal_sent_bytes_total",
+            "counter",
+            "Session payload bytes sent to the server",
+            &labels,
+            self.stats.sent.bytes.to_string(),
+        );
+        metric(
+            "rtal_received_bytes_total",
+            "counter",
+            "Session payload bytes received from the server",
+            &labels,
+            self.stats.received.bytes.to_string(),
+        );
+        let outcome = format!(
+            "{},outcome=\"{}\"",
+            labels,
+            if success { "success" } else { "failure" }
+        );
+        metric(
+            "rtal_runs_total",
+            "counter",
+            "Runs of the client by outcome",
+            &outcome,
+            "1".to_string(),
+        );
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ProgramOptions {
+    forward_signals: bool,
+    on_session_end: SessionEnd,
+    nice: Option<i32>,
+    limits: Limits,
+}
+
+/// Resource limits set on the program right before it starts.
+#[derive(Debug, Clone, Default)]
+struct Limits {
+    cpu: Option<u64>,
+    // In bytes
+    memory: Option<u64>,
+    files: Option<u64>,
+}
+
+impl Limits {
+    fn any(&self) -> bool {
+        self.cpu.is_some() || self.memory.is_some() || self.files.is_some()
+    }
+
+    /// Runs in the forked child, so it only calls setrlimit.
+    #[cfg(unix)]
+    fn apply(&self) -> std::io::Result<()> {
+        // The hard CPU limit is one second later, to deliver SIGXCPU before SIGKILL
+        let limits = [
+            (libc::RLIMIT_CPU, self.cpu.map(|x| (x, x.saturating_add(1)))),
+            (libc::RLIMIT_AS, self.memory.map(|x| (x, x))),
+            (libc::RLIMIT_NOFILE, self.files.map(|x| (x, x))),
+        ];
+        for (resource, values) in limits {
+            if let Some((soft, hard)) = values {
+                let limit = libc::rlimit {
+                    rlim_cur: soft as libc::rlim_t,
+                    rlim_max: hard as libc::rlim_t,
+                };
+                if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells whether a limit is the likely reason why the program exited with `status`.
+    fn report(&self, status: std::process::ExitStatus) {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            // Only the soft limit is known for sure, SIGKILL may as well come from the OOM killer
+            if let (Some(libc::SIGXCPU), Some(x)) = (status.signal(), self.cpu) {
+                warn!("Program killed after using up its {} s --limit-cpu", x);
+                return;
+            }
+        }
+        if let (false, Some(x)) = (status.success(), self.memory) {
+            warn!(
+                "Program failed with --limit-mem {} MiB, an allocation may have been refused",
+                x >> 20
+            );
+        }
+    }
+}
+
+fn program_command(program: &[String]) -> proc::Command {
+    let mut prog = proc::Command::new(&program[0]);
+    if program.len() > 1 {
+        prog.args(&program[1..]);
+    }
+    prog
+}
+
+struct Program {
+    child: proc::Child,
+    forwarder: Option<JoinHandle<()>>,
+    on_session_end: SessionEnd,
+    limits: Limits,
+}
+
+impl Program {
+    /// Starts `command`, which reads the stdin of rtal when `inherits_stdin`.
+    fn spawn(
+        mut command: proc::Command,
+        options: &ProgramOptions,
+        inherits_stdin: bool,
+    ) -> Result<Program, RtalError> {
+        // A background process group reading the terminal would be stopped by SIGTTIN
+        #[cfg(unix)]
+        let own_group =
+            options.forward_signals && !(inherits_stdin && std::io::stdin().is_terminal());
+        #[cfg(unix)]
+        if own_group {
+            // A process group of its own keeps the terminal from signalling the program directly
+            unsafe {
+                command.pre_exec(|| {
+                    setpgid(Pid::from_raw(0), Pid::from_raw(0)).map_err(std::io::Error::from)
+                });
+            }
+        }
+        #[cfg(unix)]
+        if options.limits.any() {
+            let limits = options.limits.clone();
+            unsafe {
+                command.pre_exec(move || limits.apply());
+            }
+        }
+        #[cfg(not(unix))]
+        if options.limits.any() {
+            warn!("Resource limits are only supported on Unix, the program runs without them");
+        }
+        // Set before exec, so that the program and whatever it forks early start at that priority
+        #[cfg(unix)]
+        if let Some(nice) = options.nice {
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        #[cfg(windows)]
+        if let Some(nice) = options.nice {
+            command.creation_flags(match nice {
+                i32::MIN..=-1 => ABOVE_NORMAL_PRIORITY_CLASS,
+                0 => NORMAL_PRIORITY_CLASS,
+                1..=9 => BELOW_NORMAL_PRIORITY_CLASS,
+                _ => IDLE_PRIORITY_CLASS,
+            });
+        }
+        let child = match command.spawn() {
+            Ok(x) => x,
+            Err(x)
+                if matches!(options.nice, Some(n) if n < 0)
+                    && x.kind() == std::io::ErrorKind::PermissionDenied =>
+            {
+                return Err(RtalError::ProcessSpawn(format!(
+                    "Cannot spawn program, a negative niceness may need privileges: {}",
+                    x
+                )))
+            }
+            Err(x) => {
+                return Err(RtalError::ProcessSpawn(format!(
+                    "Cannot spawn program: {}",
+                    x
+                )))
+            }
+        };
+        #[cfg(unix)]
+        let forwarder = match child.id() {
+            Some(pid) if options.forward_signals => {
+                forward_signals(Pid::from_raw(pid as i32), own_group)
+            }
+            _ => None,
+        };
+        // On Windows Ctrl-C already reaches every process attached to the console
+        #[cfg(not(unix))]
+        let forwarder = {
+            let _ = inherits_stdin;
+            if options.forward_signals {
+                warn!("Signal forwarding is only supported on Unix");
+            }
+            None
+        };
+        Ok(Program {
+            child,
+            forwarder,
+            on_session_end: options.on_session_end.clone(),
+            limits: options.limits.clone(),
+        })
+    }
+
+    async fn finish(
+        mut self,
+        result: Result<Vec<String>, RtalError>,
+    ) -> Result<Vec<String>, RtalError> {
+        let result = match result {
+            Ok(y) => match self.child.wait().await {
+                Ok(x) if x.success() => Ok(y),
+                Ok(x) => {
+                    warn!("Program exited with non-zero code: {}", x);
+                    self.limits.report(x);
+                    Ok(y)
+                }
+                Err(x) => {
+                    warn!("Program exited abruptly: {}", x);
+                    Ok(y)
+                }
+            },
+            Err(x) => {
+                match self.child.try_wait() {
+                    Ok(Some(status)) if !status.success() => {
+                        warn!("Program exited with non-zero code: {}", status);
+                        self.limits.report(status);
+                    }
+                    _ => self.stop().await,
+                }
+                Err(x)
+            }
+        };
+        result
+    }
+
+    async fn stop(&mut self) {
+        match self.on_session_end {
+            SessionEnd::Kill => drop(self.child.kill().await),
+            #[cfg(unix)]
+            SessionEnd::TermThenKill => {
+                if let Some(pid) = self.child.id() {
+                    if let Err(x) = kill(Pid::from_raw(pid as i32), Signal::SIGTERM) {
+                        warn!("Cannot send SIGTERM to the program: {}", x);
+                    }
+                    let grace = Duration::from_secs_f64(TERM_GRACE_PERIOD);
+                    if timeout(grace, self.child.wait()).await.is_ok() {
+                        return;
+                    }
+                    warn!("Program still running after SIGTERM, killing it");
+                }
+                drop(self.child.kill().await);
+            }
+            // Windows has no SIGTERM to ask the program to exit
+            #[cfg(not(unix))]
+            SessionEnd::TermThenKill => drop(self.child.kill().await),
+            SessionEnd::Detach => {
+                if let Some(pid) = self.child.id() {
+                    info!("Leaving the program running with pid {}", pid);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Program {
+    fn drop(&mut self) {
+        if let Some(forwarder) = self.forwarder.take() {
+            forwarder.abort();
+            #[cfg(unix)]
+            stop_forwarding_signals();
+        }
+    }
+}
+
+/// Shows what the server sent after the program crashed with `status` and how it ended the
+/// session, for --crash-grace.
+fn print_crash(
+    status: std::process::ExitStatus,
+    trailing: &[u8],
+    ended: &Result<Vec<String>, String>,
+) {
+    if trailing.is_empty() {
+        println!(
+            "> The server sent nothing after the program crashed with {}",
+            status
+        );
+    } else {
+        println!(
+            "> The server replied to the program crashing with {}:",
+            status
+        );
+        println!("{}", String::from_utf8_lossy(trailing).trim_end());
+    }
+    match ended {
+        Ok(_) => println!("> The server ended the session normally"),
+        Err(x) => println!("> The server ended the session with: {}", x),
+    }
+}
+
+/// Waits for the program to exit, forever if there is none.
+async fn wait_program(
+    program: &mut Option<&mut proc::Child>,
+) -> std::io::Result<std::process::ExitStatus> {
+    match program {
+        Some(x) => x.wait().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Handlers that tokio installed to forward SIGINT and SIGTERM, put aside between programs.
+#[cfg(unix)]
+static FORWARDING_HANDLERS: std::sync::Mutex<Vec<(Signal, SigAction)>> =
+    std::sync::Mutex::new(Vec::new());
+
+/// Gives SIGINT and SIGTERM back their default action, tokio never removes the handlers it
+/// installs and would leave rtal deaf to Ctrl-C once the program is gone.
+#[cfg(unix)]
+fn stop_forwarding_signals() {
+    let default = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+    let mut saved = FORWARDING_HANDLERS
+        .lock()
+        .unwrap_or_else(|x| x.into_inner());
+    for sig in [Signal::SIGINT, Signal::SIGTERM] {
+        match unsafe { sigaction(sig, &default) } {
+            Ok(x) => saved.push((sig, x)),
+            Err(x) => warn!("Cannot restore the default action of {}: {}", sig, x),
+        }
+    }
+}
+
+/// Forwards SIGINT and SIGTERM to the process group `pid` when `group`, otherwise the program
+/// shares the terminal and its group, gets Ctrl-C on its own and only SIGTERM is relayed.
+#[cfg(unix)]
+fn forward_signals(pid: Pid, group: bool) -> Option<JoinHandle<()>> {
+    // tokio installs its handlers once, put back the ones a previous program left
+    for (sig, action) in FORWARDING_HANDLERS
+        .lock()
+        .unwrap_or_else(|x| x.into_inner())
+        .drain(..)
+    {
+        if let Err(x) = unsafe { sigaction(sig, &action) } {
+            warn!("Cannot listen for {}: {}", sig, x);
+        }
+    }
+    let (mut interrupt, mut terminate) = match (
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::terminate()),
+    ) {
+        (Ok(x), Ok(y)) => (x, y),
+        (Err(x), _) | (_, Err(x)) => {
+            warn!("Cannot listen for signals: {}", x);
+            return None;
+        }
+    };
+    Some(tokio::spawn(async move {
+        loop {
+            let sig = select! {
+                _ = interrupt.recv() => Signal::SIGINT,
+                _ = terminate.recv() => Signal::SIGTERM,
+            };
+            let result = match (group, sig) {
+                (true, _) => killpg(pid, sig),
+                (false, Signal::SIGTERM) => kill(pid, sig),
+                _ => continue,
+            };
+            if let Err(x) = result {
+                warn!("Cannot forward {} to the program: {}", sig, x);
+            }
+        }
+    }))
+}
+
+/// State shared by the paths that move a message between the program and the server, so that
+/// every message goes through the same checks and copies whatever triggered it.
+struct Link<'a, T, Y> {
+    wsout: &'a mut T,
+    pipeout: Y,
+    options: &'a SessionOptions,
+    stats: &'a mut SessionStats,
+    log: &'a mut SessionLog,
+    client_ended: String,
+    // The --eof-marker line was read, the server is told once the delayed messages are out
+    eof_pending: bool,
+    eof_sent: bool,
+    // The program input is over, ConnectStop follows once the delayed messages are out
+    input_ended: bool,
+    // ConnectStop waits for the program to exit, to tell whether it crashed
+    hold_end: bool,
+    closing: bool,
+    // When the first program message went out, the first response latency counts from there
+    first_sent: Option<Instant>,
+    last_activity: TokioInstant,
+    watchdog: Option<LoopWatchdog>,
+    echo: bool,
+    echo_writer: EchoWriter,
+    last_print_client: Option<bool>,
+    echo_column: usize,
+    ansi: Option<AnsiFilter>,
+    utf8_tail: Vec<u8>,
+    utf8_offset: usize,
+    chaos_rng: Option<StdRng>,
+    // Messages held back by --chaos with their release time, towards the server and the program
+    delayed_out: VecDeque<(TokioInstant, Vec<u8>)>,
+    delayed_in: VecDeque<(TokioInstant, Vec<u8>)>,
+    verdicts: Option<VerdictFilter>,
+    // What the server sent after the program crashed, kept for the report
+    trailing: Option<Vec<u8>>,
+}
+
+impl<'a, T, Y> Link<'a, T, Y>
+where
+    T: Sink<Message> + Unpin,
+    <T as Sink<Message>>::Error: Display,
+    Y: AsyncWrite + Unpin,
+{
+    fn new(
+        wsout: &'a mut T,
+        pipeout: Y,
+        options: &'a SessionOptions,
+        stats: &'a mut SessionStats,
+        log: &'a mut SessionLog,
+    ) -> Result<Self, RtalError> {
+        let client_ended = Request::ConnectStop {};
+        let client_ended = match client_ended.forge() {
+            Ok(x) => x,
+            Err(x) => return Err(RtalError::Io(format!("Cannot forge request: {}", x))),
+        };
+        Ok(Link {
+            wsout,
+            pipeout,
+            options,
+            stats,
+            log,
+            client_ended,
+            eof_pending: false,
+            eof_sent: false,
+            input_ended: false,
+            hold_end: false,
+            closing: false,
+            first_sent: None,
+            last_activity: TokioInstant::now(),
+            watchdog: options.loop_limit.map(LoopWatchdog::new),
+            echo: options.echo,
+            echo_writer: EchoWriter::new(
+                options.echo_overflow,
+                options.echo || options.verdict.is_some(),
+            ),
+            last_print_client: None,
+            echo_column: 0,
+            ansi: if options.echo_strip_ansi {
+                Some(AnsiFilter::new())
+            } else {
+                None
+            },
+            utf8_tail: Vec::new(),
+            utf8_offset: 0,
+            chaos_rng: options
+                .chaos
+                .as_ref()
+                .map(|x| StdRng::seed_from_u64(x.seed.unwrap_or_default())),
+            delayed_out: VecDeque::new(),
+            delayed_in: VecDeque::new(),
+            verdicts: options
+                .verdict
+                .clone()
+                .map(|x| VerdictFilter::new(x, options.colored)),
+            trailing: None,
+        })
+    }
+
+    /// Tells the server that the program will send nothing more, at most once and after the
+    /// messages still held back by --chaos.
+    async fn end_input(&mut self) -> Result<(), RtalError> {
+        self.input_ended = true;
+        if self.closing || self.hold_end || !self.delayed_out.is_empty() {
+            return Ok(());
+        }
+        self.closing = true;
+        match self
+            .wsout
+            .send(Message::Text(self.client_ended.clone()))
+            .await
+        {
+            Ok(()) => Ok(()),
+            Err(x) => Err(RtalError::PeerClosed(format!(
+                "Cannot send data to server: {}",
+                x
+            ))),
+        }
+    }
+
+    /// Tells the server that the program input is over while its replies are still read, at
+    /// most once and after the messages still held back by --chaos.
+    async fn send_eof(&mut self) -> Result<(), RtalError> {
+        self.eof_pending = true;
+        if self.eof_sent || self.closing || !self.delayed_out.is_empty() {
+            return Ok(());
+        }
+        self.eof_sent = true;
+        let request = Request::ConnectEof {};
+        let request = match request.forge() {
+            Ok(x) => x,
+            Err(x) => return Err(RtalError::Io(format!("Cannot forge request: {}", x))),
+        };
+        match self.wsout.send(Message::Text(request)).await {
+            Ok(()) => Ok(()),
+            Err(x) => Err(RtalError::PeerClosed(format!(
+                "Cannot send data to server: {}",
+                x
+            ))),
+        }
+    }
+
+    /// Sends a message of the program to the server, up to the --eof-marker line.
+    async fn send(&mut self, mut data: Vec<u8>) -> Result<(), RtalError> {
+        if let Some(ref marker) = self.options.eof_marker {
+            if let Some(x) = find_marker_line(&data, marker.as_bytes()) {
+                data.truncate(x);
+                if !data.is_empty() {
+                    self.send_message(data).await?;
+                }
+                return self.send_eof().await;
+            }
+        }
+        self.send_message(data).await
+    }
+
+    async fn send_message(&mut self, data: Vec<u8>) -> Result<(), RtalError> {
+        // A message holds whole lines when rules are set, each line is checked on its own
+        for line in data.split_inclusive(|&x| x == b'\n') {
+            if let Err(x) = self.options.outbound.check(line) {
+                return Err(RtalError::InvalidOutbound(format!(
+                    "Program line {:?} {}",
+                    String::from_utf8_lossy(line),
+                    x
+                )));
+            }
+        }
+        if let (Some(chaos), Some(rng)) = (&self.options.chaos, &mut self.chaos_rng) {
+            match chaos.plan(rng) {
+                Some(x) => delay_message(&mut self.delayed_out, x, data),
+                None => debug!("Chaos: dropped {} bytes from the program", data.len()),
+            }
+            return Ok(());
+        }
+        self.forward_to_server(data).await
+    }
+
+    async fn forward_to_server(&mut self, data: Vec<u8>) -> Result<(), RtalError> {
+        if self.options.echo_filter != EchoFilter::In {
+            self.echo(&data, true).await;
+        }
+        self.stats.sent.record(data.len());
+        if let Some(ref mut acks) = self.stats.acks {
+            acks.sent(self.stats.sent.messages);
+        }
+        self.log
+            .record(self.stats.sent.messages, "C>S", &data)
+            .await;
+        if let Some(ref mut watchdog) = self.watchdog {
+            if watchdog.bounced(&data, true) {
+                return Err(watchdog.error());
+            }
+        }
+        self.last_activity = TokioInstant::now();
+        if self.first_sent.is_none() {
+            self.first_sent = Some(Instant::now());
+        }
+        let data = self.options.framing.wrap(data);
+        send_data(
+            self.wsout,
+            data,
+            self.options.max_frame_payload,
+            self.options.e2e.as_deref(),
+        )
+        .await
+    }
+
+    /// Forwards a message of the server to the program.
+    async fn receive(&mut self, data: Vec<u8>) -> Result<(), RtalError> {
+        let data = match self.options.e2e {
+            Some(ref key) => key
+                .open(Direction::ToClient, &data)
+                .map_err(|x| RtalError::Integrity(format!("Cannot open server message: {}", x)))?,
+            None => data,
+        };
+        self.stats.received.record(data.len());
+        if let Some(ref mut acks) = self.stats.acks {
+            acks.received(&data);
+        }
+        self.log
+            .record(self.stats.received.messages, "S>C", &data)
+            .await;
+        let data = self.options.framing.strip(data);
+        if let Some(ref mut watchdog) = self.watchdog {
+            if watchdog.bounced(&data, false) {
+                return Err(watchdog.error());
+            }
+        }
+        if let (Some(chaos), Some(rng)) = (&self.options.chaos, &mut self.chaos_rng) {
+            match chaos.plan(rng) {
+                Some(x) => delay_message(&mut self.delayed_in, x, data),
+                None => debug!("Chaos: dropped {} bytes from the server", data.len()),
+            }
+            return Ok(());
+        }
+        self.forward_to_program(data).await
+    }
+
+    async fn forward_to_program(&mut self, data: Vec<u8>) -> Result<(), RtalError> {
+        if self.options.strict_utf8 {
+            if let Err(offset) = check_utf8(&data, &mut self.utf8_tail, &mut self.utf8_offset) {
+                return Err(RtalError::Server(format!(
+                    "Server sent invalid UTF-8 at byte {}",
+                    offset
+                )));
+            }
+        }
+        self.last_activity = TokioInstant::now();
+        if let (None, Some(x)) = (self.stats.first_response, self.first_sent) {
+            self.stats.first_response = Some(x.elapsed());
+        }
+        let data = match self.verdicts {
+            Some(ref mut filter) => {
+                let (data, verdicts) = filter.filter(&data);
+                self.print_verdicts(verdicts).await;
+                data
+            }
+            None => data,
+        };
+        self.deliver(data).await
+    }
+
+    /// Passes the server output left by --parse-verdict on to the program.
+    async fn deliver(&mut self, data: Vec<u8>) -> Result<(), RtalError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        if self.options.echo_filter != EchoFilter::Out {
+            self.echo(&data, false).await;
+        }
+        if let Some(ref pattern) = self.options.fail_fast {
+            let text = String::from_utf8_lossy(&data);
+            if let Some(line) = text.lines().find(|x| pattern.is_match(x)) {
+                return Err(RtalError::Server(format!(
+                    "Server reported an error: {}",
+                    line
+                )));
+            }
+        }
+        if let Some(ref mut x) = self.trailing {
+            x.extend_from_slice(&data);
+            return Ok(());
+        }
+        let mut close = false;
+        if let Err(x) = self.pipeout.write_all(&data).await {
+            warn!("Cannot write to user: {}", x);
+            close = true;
+        }
+        if let Err(x) = self.pipeout.flush().await {
+            warn!("Cannot flush stream: {}", x);
+            close = true;
+        }
+        if close {
+            self.end_input().await?;
+        }
+        Ok(())
+    }
+
+    /// When the next message held back by --chaos is due.
+    fn next_release(&self) -> Option<TokioInstant> {
+        [self.delayed_out.front(), self.delayed_in.front()]
+            .into_iter()
+            .flatten()
+            .map(|x| x.0)
+            .min()
+    }
+
+    /// Delivers the messages held back by --chaos whose time has come.
+    async fn release(&mut self) -> Result<(), RtalError> {
+        let now = TokioInstant::now();
+        while matches!(self.delayed_out.front(), Some((x, _)) if *x <= now) {
+            let (_, data) = self.delayed_out.pop_front().expect("Cannot fail");
+            self.forward_to_server(data).await?;
+        }
+        if self.eof_pending {
+            self.send_eof().await?;
+        }
+        while matches!(self.delayed_in.front(), Some((x, _)) if *x <= now) {
+            let (_, data) = self.delayed_in.pop_front().expect("Cannot fail");
+            self.forward_to_program(data).await?;
+        }
+        if self.input_ended {
+            self.end_input().await?;
+        }
+        Ok(())
+    }
+
+    /// Ends the session on the server request, delivering at once what --chaos still holds
+    /// back for the program.
+    async fn stop(&mut self) -> Result<(), RtalError> {
+        // The server is done, there is nothing left to wait for the program exit
+        self.hold_end = false;
+        self.delayed_out.clear();
+        while let Some((_, data)) = self.delayed_in.pop_front() {
+            self.forward_to_program(data).await?;
+        }
+        if let Some(ref mut filter) = self.verdicts {
+            let (data, verdicts) = filter.flush();
+            self.print_verdicts(verdicts).await;
+            self.deliver(data).await?;
+        }
+        self.end_input().await
+    }
+
+    async fn print_verdicts(&mut self, verdicts: Vec<String>) {
+        for line in verdicts {
+            // Behind the echo queued so far, so that the verdict comes last
+            self.echo_writer.print(format!("{}\n", line)).await;
+        }
+    }
+
+    async fn echo(&mut self, data: &[u8], client: bool) {
+        if !self.echo {
+            return;
+        }
+        let mut text = String::new();
+        if self.last_print_client != Some(client) {
+            text.push_str(if client { "[CLIENT]\n" } else { "[SERVER]\n" });
+            self.last_print_client = Some(client);
+            self.echo_column = 0;
+        }
+        let display = match self.ansi {
+            Some(ref mut x) => x.strip(data, client),
+            None => String::from_utf8_lossy(data).into_owned(),
+        };
+        text.push_str(&echo_text(
+            &display,
+            self.options.echo_wrap,
+            &mut self.echo_column,
+        ));
+        if !self.echo_writer.write(text).await {
+            self.echo = false;
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_connection<
+    T: Sink<Message> + Unpin,
+    U: Stream<Item = Result<Message, TsError>> + Unpin,
+    X: AsyncRead + Unpin,
+    Y: AsyncWrite + Unpin,
+>(
+    wsout: &mut T,
+    wsin: &mut U,
+    mut pipein: X,
+    pipeout: Y,
+    mut program: Option<&mut proc::Child>,
+    options: &SessionOptions,
+    stats: &mut SessionStats,
+    log: &mut SessionLog,
+) -> Result<Vec<String>, RtalError>
+where
+    <T as Sink<Message>>::Error: Display,
+{
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut pending_cr = false;
+    let max_first_response = Duration::from_millis(options.max_first_response.unwrap_or_default());
+    let idle_duration = Duration::from_secs_f64(options.idle_warning.unwrap_or_default());
+    // Last activity already warned about
+    let mut idle_warned = None;
+    let mut line_buffer = Vec::new();
+    // The outbound rules are about lines, a read may end anywhere in one
+    let line_mode = options.line_mode || options.outbound.is_set();
+    let post_connect = sleep(Duration::from_millis(
+        options.post_connect_delay.unwrap_or_default(),
+    ));
+    tokio::pin!(post_connect);
+    let mut forwarding = options.post_connect_delay.is_none();
+    let mut pinger = interval(Duration::from_millis(
+        options.ping_interval.unwrap_or(1).max(1),
+    ));
+    // Sequence number and send time of the ping waiting for its pong
+    let mut ping_sent: Option<(u64, Instant)> = None;
+    let mut flusher = interval(Duration::from_secs_f64(
+        options.debug_seq_flush.unwrap_or(1.0).max(0.1),
+    ));
+    let mut ping_seq = 0u64;
+    let mut pings_missed = 0;
+    let mut pause = PauseSignal::new(options.pausable);
+    let mut paused = false;
+    // Server data arrived while paused with --pause-inbound
+    let mut held = VecDeque::new();
+    let deadline = sleep_until(options.deadline.unwrap_or_else(TokioInstant::now));
+    tokio::pin!(deadline);
+    let crash_grace = Duration::from_millis(options.crash_grace.unwrap_or_default());
+    let grace = sleep(crash_grace);
+    tokio::pin!(grace);
+    let mut running = program.is_some();
+    // Exit status of the crashed program
+    let mut crashed = None;
+    let mut link = Link::new(wsout, pipeout, options, stats, log)?;
+    link.hold_end = running && options.crash_grace.is_some();
+    loop {
+        let idle_timeout = sleep_until(link.last_activity + idle_duration);
+        let release = link.next_release();
+        let first_response_due = match (link.first_sent, link.stats.first_response) {
+            (Some(x), None) if options.max_first_response.is_some() => {
+                Some(TokioInstant::from_std(x) + max_first_response)
+            }
+            _ => None,
+        };
+        select! {
+            msg = wsin.next() => match msg {
+                Some(Ok(Message::Binary(x))) if !link.closing => {
+                    if paused && options.pause_inbound {
+                        held.push_back(x);
+                    } else {
+                        link.receive(x).await?;
+                    }
+                }
+                Some(Ok(Message::Text(x))) => match Reply::parse(&x) {
+                    Ok(Reply::ConnectStop { status }) => {
+                        while let Some(x) = held.pop_front() {
+                            link.receive(x).await?;
+                        }
+                        link.stop().await?;
+                        if let Some(x) = crashed {
+                            print_crash(x, link.trailing.as_deref().unwrap_or_default(), &status);
+                        }
+                        return status.map_err(RtalError::Server);
+                    }
+                    Ok(_) => return Err(RtalError::Server("Received wrong message from server".to_string())),
+                    Err(x) => return Err(RtalError::Server(format!("Cannot parse server reply: {}", x))),
+                },
+                Some(Ok(Message::Pong(x))) => {
+                    if let Some((seq, sent)) = ping_sent {
+                        if x == seq.to_be_bytes() {
+                            link.stats.last_rtt = Some(sent.elapsed());
+                            ping_sent = None;
+                            pings_missed = 0;
+                        }
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(x)) => return Err(RtalError::PeerClosed(format!("Connection lost: {}", x))),
+                None => return Err(RtalError::PeerClosed("Connection lost".to_string())),
+            },
+            _ = sleep_until(first_response_due.unwrap_or_else(TokioInstant::now)), if first_response_due.is_some() => {
+                return Err(RtalError::Timeout(format!(
+                    "The server did not respond within {} ms",
+                    options.max_first_response.unwrap_or_default()
+                )));
+            }
+            _ = &mut deadline, if options.deadline.is_some() => {
+                return Err(RtalError::Deadline("The session deadline has been reached".to_string()));
+            }
+            _ = idle_timeout, if options.idle_warning.is_some() && !paused && idle_warned != Some(link.last_activity) => {
+                warn!("Still waiting: no data exchanged for {:.0} seconds", idle_duration.as_secs_f64());
+                idle_warned = Some(link.last_activity);
+            }
+            status = wait_program(&mut program), if running && link.hold_end => {
+                running = false;
+                match status {
+                    Ok(x) if !x.success() => {
+                        warn!("Program crashed with {}, waiting for the server reaction", x);
+                        crashed = Some(x);
+                        link.trailing = Some(Vec::new());
+                        grace.as_mut().reset(TokioInstant::now() + crash_grace);
+                        // The evaluator sees its input end and may explain what went wrong
+                        link.send_eof().await?;
+                    }
+                    _ => {
+                        link.hold_end = false;
+                        if link.input_ended {
+                            link.end_input().await?;
+                        }
+                    }
+                }
+            }
+            _ = &mut grace, if crashed.is_some() && link.hold_end => {
+                link.hold_end = false;
+                link.end_input().await?;
+            }
+            _ = sleep_until(release.unwrap_or_else(TokioInstant::now)), if release.is_some() => {
+                link.release().await?;
+            }
+            _ = pinger.tick(), if options.ping_interval.is_some() => {
+                if ping_sent.is_some() {
+                    pings_missed += 1;
+                    if pings_missed >= options.ping_failures.max(1) {
+                        return Err(RtalError::PeerUnresponsive(format!(
+                            "The server did not answer {} pings in a row",
+                            pings_missed
+                        )));
+                    }
+                }
+                ping_seq += 1;
+                if let Err(x) = link.wsout.send(Message::Ping(ping_seq.to_be_bytes().to_vec())).await {
+                    return Err(RtalError::PeerClosed(format!("Cannot send ping: {}", x)));
+                }
+                ping_sent = Some((ping_seq, Instant::now()));
+            }
+            _ = flusher.tick(), if options.debug_seq_flush.is_some() => {
+                link.log.flush().await;
+            }
+            _ = &mut post_connect, if !forwarding => {
+                forwarding = true;
+            }
+            _ = pause.recv() => {
+                paused = !paused;
+                if paused {
+                    warn!("Forwarding paused, send SIGUSR1 again to resume");
+                } else {
+                    warn!("Forwarding resumed");
+                    // The idle time starts over, a pause is not the server being silent
+                    link.last_activity = TokioInstant::now();
+                    while let Some(x) = held.pop_front() {
+                        link.receive(x).await?;
+                    }
+                }
+            }
+            size = pipein.read(&mut buffer), if !link.input_ended && !link.eof_pending && forwarding && !paused => {
+                let size = match size {
+                    Ok(0) => {
+                        if pending_cr {
+                            line_buffer.push(b'\r');
+                        }
+                        // The last line may lack its newline, it still is a program message
+                        if !line_buffer.is_empty() {
+                            link.send(std::mem::take(&mut line_buffer)).await?;
+                        }
+                        link.end_input().await?;
+                        continue;
+                    }
+                    Ok(x) => x,
+                    Err(x) => {
+                        warn!("Cannot read from user: {}", x);
+                        link.end_input().await?;
+                        continue;
+                    }
+                };
+                let data = if options.normalize_newlines {
+                    normalize_newlines(&buffer[..size], &mut pending_cr)
+                } else {
+                    buffer[..size].to_vec()
+                };
+                let data = if line_mode {
+                    line_buffer.extend_from_slice(&data);
+                    match line_buffer.iter().rposition(|&x| x == b'\n') {
+                        Some(x) => line_buffer.drain(..=x).collect(),
+                        None => continue,
+                    }
+                } else {
+                    data
+                };
+                if !data.is_empty() {
+                    link.send(data).await?;
+                }
+            }
+        }
+    }
+}
+
+/// Where the first line equal to the marker starts, ignoring its line ending.
+fn find_marker_line(data: &[u8], marker: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    for line in data.split_inclusive(|&x| x == b'\n') {
+        let text = line.strip_suffix(b"\n").unwrap_or(line);
+        let text = text.strip_suffix(b"\r").unwrap_or(text);
+        if text == marker {
+            return Some(start);
+        }
+        start += line.len();
+    }
+    None
+}
+
+/// SIGUSR1 deliveries for --pausable, never resolving when disabled or unsupported.
+struct PauseSignal {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl PauseSignal {
+    fn new(enabled: bool) -> PauseSignal {
+        #[cfg(unix)]
+        {
+            // Listening replaces the default action of SIGUSR1, which is to terminate
+            if !enabled {
+                return PauseSignal { signal: None };
+            }
+            match signal(SignalKind::user_defined1()) {
+                Ok(x) => PauseSignal { signal: Some(x) },
+                Err(x) => {
+                    warn!("Cannot listen for SIGUSR1, pausing is disabled: {}", x);
+                    PauseSignal { signal: None }
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            if enabled {
+                warn!("Pausing with SIGUSR1 is only supported on Unix");
+            }
+            PauseSignal {}
+        }
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(ref mut x) = self.signal {
+            if x.recv().await.is_some() {
+                return;
+            }
+        }
+        std::future::pending::<()>().await
+    }
+}
+
+/// Copies of the session messages kept for --debug-seq, --scorer and --mirror-to.
+struct SessionLog {
+    debug_seq: Option<BufWriter<tokio::fs::File>>,
+    transcript: Option<Vec<u8>>,
+    mirror: Option<mpsc::Sender<String>>,
+    mirror_dropped: u64,
+}
+
+impl SessionLog {
+    /// Writes out what is still buffered, the file is complete only afterwards.
+    async fn finish(&mut self) {
+        if self.mirror_dropped > 0 {
+            warn!("Dropped {} mirrored messages in total", self.mirror_dropped);
+        }
+        self.flush().await;
+    }
+
+    /// Writes out the lines recorded so far, the file then holds whole lines only.
+    async fn flush(&mut self) {
+        if let Some(ref mut x) = self.debug_seq {
+            if let Err(x) = x.flush().await {
+                warn!("Cannot write the debug sequence file: {}", x);
+                self.debug_seq = None;
+            }
+        }
+    }
+
+    async fn record(&mut self, seq: u64, direction: &str, data: &[u8]) {
+        let time = Utc::now().to_rfc3339();
+        if let Some(ref mut x) = self.debug_seq {
+            let line = format!(
+                "{} {} {} {} \"{}\"\n",
+                seq,
+                direction,
+                time,
+                data.len(),
+                String::from_utf8_lossy(data).escape_debug()
+            );
+            // Give up on the file at the first error rather than failing the session
+            if let Err(x) = x.write_all(line.as_bytes()).await {
+                warn!("Cannot write the debug sequence file: {}", x);
+                self.debug_seq = None;
+            }
+        }
+        if let Some(ref mut x) = self.transcript {
+            x.extend_from_slice(format!("{} {} {}\n", direction, time, data.len()).as_bytes());
+            x.extend_from_slice(data);
+            x.push(b'\n');
+        }
+        if let Some(ref x) = self.mirror {
+            let event = serde_json::json!({
+                "seq": seq,
+                "direction": direction,
+                "time": time,
+                "data": String::from_utf8_lossy(data),
+            });
+            // A slow observer loses messages instead of slowing down the session
+            match x.try_send(event.to_string()) {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    if self.mirror_dropped == 0 {
+                        warn!("Observer is too slow, dropping mirrored messages");
+                    }
+                    self.mirror_dropped += 1;
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    warn!("Observer went away, no longer mirroring the session");
+                    self.mirror = None;
+                }
+            }
+        }
+    }
+}
+
+/// Opens the observer endpoint of --mirror-to. Messages only ever flow towards the observer,
+/// whatever it sends back is discarded.
+async fn open_mirror(target: &str) -> Result<mpsc::Sender<String>, RtalError> {
+    let (sender, mut receiver) = mpsc::channel::<String>(MIRROR_QUEUE);
+    if target.starts_with("ws://") || target.starts_with("wss://") {
+        let ws = match connect_async(target).await {
+            Ok(x) => x.0,
+            Err(x) => {
+                return Err(RtalError::SocketConfig(format!(
+                    "Cannot connect to observer \"{}\": {}",
+                    target, x
+                )))
+            }
+        };
+        let (mut wsout, mut wsin) = ws.split();
+        tokio::spawn(async move {
+            loop {
+                select! {
+                    event = receiver.recv() => match event {
+                        Some(x) => if let Err(x) = wsout.send(Message::Text(x)).await {
+                            warn!("Cannot send to observer: {}", x);
+                            break;
+                        },
+                        None => break,
+                    },
+                    msg = wsin.next() => if msg.is_none() {
+                        break;
+                    },
+                }
+            }
+            drop(wsout.close().await);
+        });
+        return Ok(sender);
+    }
+    #[cfg(unix)]
+    if let Some(path) = target.strip_prefix("unix:") {
+        let stream = match UnixStream::connect(path).await {
+            Ok(x) => x,
+            Err(x) => {
+                return Err(RtalError::SocketConfig(format!(
+                    "Cannot connect to observer \"{}\": {}",
+                    target, x
+                )))
+            }
+        };
+        tokio::spawn(mirror_lines(stream, receiver));
+        return Ok(sender);
+    }
+    let stream = match TcpStream::connect(target).await {
+        Ok(x) => x,
+        Err(x) => {
+            return Err(RtalError::SocketConfig(format!(
+                "Cannot connect to observer \"{}\": {}",
+                target, x
+            )))
+        }
+    };
+    tokio::spawn(mirror_lines(stream, receiver));
+    Ok(sender)
+}
+
+/// Writes mirrored messages to a stream socket as JSON lines, read half left untouched.
+async fn mirror_lines<W: AsyncWrite + Unpin>(mut stream: W, mut receiver: mpsc::Receiver<String>) {
+    while let Some(mut x) = receiver.recv().await {
+        x.push('\n');
+        if let Err(x) = stream.write_all(x.as_bytes()).await {
+            warn!("Cannot send to observer: {}", x);
+            break;
+        }
+    }
+}
+
+/// Runs the scorer with the transcript on stdin and prints what it writes on stdout. The
+/// transcript is a sequence of `<C>S|S>C> <RFC 3339 time> <length>` lines, each followed by
+/// `<length>` bytes of payload and a newline.
+async fn run_scorer(command: &str, transcript: Vec<u8>) -> Result<(), RtalError> {
+    #[cfg(unix)]
+    let mut prog = {
+        let mut prog = proc::Command::new("sh");
+        prog.arg("-c");
+        prog
+    };
+    #[cfg(not(unix))]
+    let mut prog = {
+        let mut prog = proc::Command::new("cmd");
+        prog.arg("/C");
+        prog
+    };
+    prog.arg(command);
+    prog.stdin(Stdio::piped());
+    prog.stdout(Stdio::piped());
+    let mut child = match prog.spawn() {
+        Ok(x) => x,
+        Err(x) => {
+            return Err(RtalError::ProcessSpawn(format!(
+                "Cannot spawn scorer: {}",
+                x
+            )))
+        }
+    };
+    let mut stdin = child.stdin.take().expect("Cannot fail");
+    // Feed stdin on its own so that a scorer writing a lot before reading cannot deadlock
+    let writer = tokio::spawn(async move { stdin.write_all(&transcript).await });
+    let output = match child.wait_with_output().await {
+        Ok(x) => x,
+        Err(x) => return Err(RtalError::Io(format!("Cannot run scorer: {}", x))),
+    };
+    if let Ok(Err(x)) = writer.await {
+        warn!("Cannot send the transcript to the scorer: {}", x);
+    }
+    println!(
+        "> Scorer: {}",
+        String::from_utf8_lossy(&output.stdout).trim_end()
+    );
+    // The code of the scorer is only reported, rtal keeps its own stable exit codes
+    match output.status.code() {
+        _ if output.status.success() => Ok(()),
+        Some(x) => Err(RtalError::Scorer(format!(
+            "Scorer rejected the session with exit code {}",
+            x
+        ))),
+        None => Err(RtalError::Scorer(format!(
+            "Scorer rejected the session: {}",
+            output.status
+        ))),
+    }
+}
+
+/// Validates a piece of the server output, keeping in `tail` a multibyte character that
+/// continues in the next message. On error returns the offset of the bad byte in the session.
+fn check_utf8(data: &[u8], tail: &mut Vec<u8>, offset: &mut usize) -> Result<(), usize> {
+    tail.extend_from_slice(data);
+    match std::str::from_utf8(tail) {
+        Ok(_) => {
+            *offset += tail.len();
+            tail.clear();
+            Ok(())
+        }
+        Err(x) if x.error_len().is_none() => {
+            *offset += x.valid_up_to();
+            tail.drain(..x.valid_up_to());
+            Ok(())
+        }
+        Err(x) => Err(*offset + x.valid_up_to()),
+    }
+}
+
+/// Removes the ANSI escape sequences from the echo for --echo-strip-ansi.
+struct AnsiFilter {
+    whole: Regex,
+    cut: Regex,
+    // Sequence cut at the end of the last message, by direction
+    tails: [String; 2],
+}
+
+impl AnsiFilter {
+    fn new() -> AnsiFilter {
+        AnsiFilter {
+            whole: Regex::new(ANSI_ESCAPE).expect("Cannot fail"),
+            cut: Regex::new(ANSI_CUT).expect("Cannot fail"),
+            tails: Default::default(),
+        }
+    }
+
+    /// Strips a message, holding back a sequence that continues in the next one.
+    fn strip(&mut self, data: &[u8], client: bool) -> String {
+        let tail = &mut self.tails[client as usize];
+        tail.push_str(&String::from_utf8_lossy(data));
+        let text = std::mem::take(tail);
+        let mut text = self.whole.replace_all(&text, "").into_owned();
+        if let Some(x) = self.cut.find(&text) {
+            if x.end() - x.start() <= ANSI_MAX_HELD {
+                *tail = text.split_off(x.start());
+            }
+        }
+        text
+    }
+}
+
+/// Formats echoed text, breaking lines at `wrap` columns with an indented continuation.
+fn echo_text(text: &str, wrap: Option<usize>, column: &mut usize) -> String {
+    let width = match wrap {
+        Some(x) if x > ECHO_WRAP_INDENT.len() => x,
+        _ => return text.to_string(),
+    };
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '\n' {
+            *column = 0;
+        } else if *column >= width {
+            result.push('\n');
+            result.push_str(ECHO_WRAP_INDENT);
+            *column = ECHO_WRAP_INDENT.len() + 1;
+        } else {
+            *column += 1;
+        }
+        result.push(c);
+    }
+    result
+}
+
+/// Echo output written by its own thread, so that a slow terminal does not stall the session.
+struct EchoWriter {
+    sender: Option<mpsc::Sender<String>>,
+    // Disconnected once the thread is done writing
+    done: Option<std::sync::mpsc::Receiver<()>>,
+    overflow: EchoOverflow,
+    dropped: u64,
+}
+
+impl EchoWriter {
+    fn new(overflow: EchoOverflow, enabled: bool) -> EchoWriter {
+        let mut writer = EchoWriter {
+            sender: None,
+            done: None,
+            overflow,
+            dropped: 0,
+        };
+        if enabled {
+            let (sender, mut receiver) = mpsc::channel::<String>(ECHO_QUEUE);
+            let (done, finished) = std::sync::mpsc::channel::<()>();
+            writer.sender = Some(sender);
+            writer.done = Some(finished);
+            std::thread::spawn(move || {
+                let _done = done;
+                while let Some(text) = receiver.blocking_recv() {
+                    if let Err(x) = write_stdout(&text) {
+                        warn!(
+                            "Cannot echo to stdout, echo is off for the rest of the session: {}",
+                            x
+                        );
+                        break;
+                    }
                 }
-                if to_write.len() > 0 {
-                    tokio::fs::create_dir_all(&output)
-                        .await
-                        .map_err(|x| format!("Cannot create {:?}: {}", &output, x))?;
+            });
+        }
+        writer
+    }
+
+    /// Queues `text` behind the echo whatever the overflow policy, for what must not be lost.
+    async fn print(&mut self, text: String) {
+        let result = match self.sender {
+            Some(ref x) => x.send(text).await.map_err(|x| x.0),
+            None => Err(text),
+        };
+        if let Err(text) = result {
+            if let Err(x) = write_stdout(&text) {
+                warn!("Cannot print to stdout: {}", x);
+            }
+        }
+    }
+
+    /// Queues `text`, false once stdout is gone.
+    async fn write(&mut self, text: String) -> bool {
+        let sender = match self.sender {
+            Some(ref x) => x,
+            None => return false,
+        };
+        let result = match self.overflow {
+            EchoOverflow::Block => sender.send(text).await.is_ok(),
+            EchoOverflow::Drop => match sender.try_send(text) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    if self.dropped == 0 {
+                        warn!("The terminal cannot keep up, dropping echo output");
+                    }
+                    self.dropped += 1;
+                    true
                 }
-                for (name, data) in to_write {
-                    let filename = output.join(name);
-                    tokio::fs::write(&filename, data)
-                        .await
-                        .map_err(|x| format!("Cannot write {:?}: {}", filename, x))?;
-                    println!("Received {:?}", &filename);
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            },
+        };
+        if !result {
+            self.sender = None;
+        }
+        result
+    }
+}
+
+impl Drop for EchoWriter {
+    fn drop(&mut self) {
+        // Let the echo of the last messages reach the terminal before the session summary, but
+        // do not hang on a terminal that is stuck
+        self.sender = None;
+        if let Some(done) = self.done.take() {
+            let drain = Duration::from_secs_f64(ECHO_DRAIN_TIME);
+            if let Err(std::sync::mpsc::RecvTimeoutError::Timeout) = done.recv_timeout(drain) {
+                warn!("The terminal is not taking the echo, leaving the rest behind");
+            }
+        }
+        if self.dropped > 0 {
+            warn!("Dropped {} echo writes in total", self.dropped);
+        }
+    }
+}
+
+/// Writes to stdout without panicking when it is gone, e.g. a pipe closed by `head`.
+fn write_stdout(text: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(text.as_bytes())?;
+    stdout.flush()
+}
+
+/// Picks the JSON verdict lines out of the server output for --parse-verdict. A line that may be
+/// a verdict is held back until complete, as the server may split it across messages.
+struct VerdictFilter {
+    keys: VerdictKeys,
+    colored: bool,
+    // Start of the current line, held back while it may be a verdict
+    line: Vec<u8>,
+    holding: bool,
+    at_start: bool,
+}
+
+impl VerdictFilter {
+    fn new(keys: VerdictKeys, colored: bool) -> VerdictFilter {
+        VerdictFilter {
+            keys,
+            colored,
+            line: Vec::new(),
+            holding: false,
+            at_start: true,
+        }
+    }
+
+    /// Returns the output to forward and the verdicts found in it.
+    fn filter(&mut self, data: &[u8]) -> (Vec<u8>, Vec<String>) {
+        let mut output = Vec::with_capacity(data.len());
+        let mut verdicts = Vec::new();
+        for &x in data {
+            if self.at_start {
+                match x {
+                    b' ' | b'\t' => {
+                        self.line.push(x);
+                        continue;
+                    }
+                    b'{' => self.holding = true,
+                    _ => output.append(&mut self.line),
                 }
-                Ok(())
+                self.at_start = false;
+            }
+            if self.holding {
+                self.line.push(x);
+            } else {
+                output.push(x);
+            }
+            if x == b'\n' {
+                self.end_line(&mut output, &mut verdicts);
+            }
+        }
+        // A whole JSON object needs no newline, e.g. a service sending one object per message
+        if self.holding && serde_json::from_slice::<serde_json::Value>(&self.line).is_ok() {
+            self.end_line(&mut output, &mut verdicts);
+        }
+        (output, verdicts)
+    }
+
+    /// Releases what is held back once the server ended the session.
+    fn flush(&mut self) -> (Vec<u8>, Vec<String>) {
+        let mut output = Vec::new();
+        let mut verdicts = Vec::new();
+        self.end_line(&mut output, &mut verdicts);
+        (output, verdicts)
+    }
+
+    fn end_line(&mut self, output: &mut Vec<u8>, verdicts: &mut Vec<String>) {
+        if self.holding {
+            if let Some(x) = format_verdict(&self.line, &self.keys, self.colored) {
+                verdicts.push(x);
+                self.line.clear();
             }
         }
+        // Not a verdict after all, or the blanks of a line that went no further
+        output.append(&mut self.line);
+        self.holding = false;
+        self.at_start = true;
     }
 }
 
-async fn handle_connection<
+/// Formats `data` as a PASS/FAIL line if it is a JSON verdict.
+fn format_verdict(data: &[u8], keys: &VerdictKeys, colored: bool) -> Option<String> {
+    let verdict = match serde_json::from_slice::<serde_json::Value>(data) {
+        Ok(serde_json::Value::Object(x)) => x,
+        _ => return None,
+    };
+    let passed = match verdict.get(&keys.result) {
+        Some(serde_json::Value::Bool(x)) => *x,
+        Some(serde_json::Value::String(x)) => matches!(
+            x.to_lowercase().as_str(),
+            "pass" | "passed" | "ok" | "success" | "accepted" | "correct"
+        ),
+        _ => return None,
+    };
+    let (label, color) = if passed {
+        ("PASS", "\x1b[1;32m")
+    } else {
+        ("FAIL", "\x1b[1;31m")
+    };
+    let mut line = if colored {
+        format!("{}{}\x1b[0m", color, label)
+    } else {
+        label.to_string()
+    };
+    if let Some(score) = verdict.get(&keys.score) {
+        line.push_str(&format!(" score: {}", score));
+    }
+    match verdict.get(&keys.message) {
+        Some(serde_json::Value::String(x)) => line.push_str(&format!(" - {}", x)),
+        Some(x) => line.push_str(&format!(" - {}", x)),
+        None => {}
+    }
+    Some(line)
+}
+
+async fn send_data<T: Sink<Message> + Unpin>(
+    wsout: &mut T,
+    data: Vec<u8>,
+    max_frame_payload: Option<usize>,
+    e2e: Option<&E2eKey>,
+) -> Result<(), RtalError>
+where
+    <T as Sink<Message>>::Error: Display,
+{
+    let messages = match max_frame_payload {
+        Some(limit) if data.len() > limit => {
+            data.chunks(limit.max(1)).map(|x| x.to_vec()).collect()
+        }
+        _ => vec![data],
+    };
+    // Each message is sealed on its own, the server opens them one by one
+    let messages = messages.into_iter().map(|x| match e2e {
+        Some(key) => Message::Binary(key.seal(Direction::ToServer, x)),
+        None => Message::Binary(x),
+    });
+    for message in messages {
+        if let Err(x) = wsout.send(message).await {
+            return Err(RtalError::PeerClosed(format!(
+                "Cannot send data to server: {}",
+                x
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Maps a failed `recv_binary_file` to the error of the part that failed.
+fn download_error(context: &str, error: RecvFileError) -> RtalError {
+    match error {
+        RecvFileError::Transfer(x) => RtalError::PeerClosed(format!("{}: {}", context, x)),
+        RecvFileError::Write(x) => RtalError::Io(format!("{}: {}", context, x)),
+        RecvFileError::Corrupted(x) => RtalError::Integrity(format!("{}: {}", context, x)),
+    }
+}
+
+async fn load_args_file(path: &Path) -> Result<Vec<(String, String)>, RtalError> {
+    let content = match tokio::fs::read_to_string(path).await {
+        Ok(x) => x,
+        Err(x) => return Err(RtalError::Io(format!("Cannot read {:?}: {}", path, x))),
+    };
+    let args: BTreeMap<String, serde_json::Value> = if path.extension().is_some_and(|x| x == "toml")
+    {
+        toml::from_str(&content)
+            .map_err(|x| RtalError::Usage(format!("Cannot parse {:?}: {}", path, x)))?
+    } else {
+        serde_json::from_str(&content)
+            .map_err(|x| RtalError::Usage(format!("Cannot parse {:?}: {}", path, x)))?
+    };
+    let mut result = Vec::new();
+    for (name, value) in args {
+        let value = match value {
+            serde_json::Value::String(x) => x,
+            serde_json::Value::Number(x) => x.to_string(),
+            serde_json::Value::Bool(x) => x.to_string(),
+            _ => {
+                return Err(RtalError::Usage(format!(
+                    "Argument {} in {:?} must be a string, a number or a boolean",
+                    name, path
+                )))
+            }
+        };
+        debug!("Argument {} from {:?}", name, path);
+        result.push((name, value));
+    }
+    Ok(result)
+}
+
+/// Converts CRLF to LF, holding back a trailing CR until the next chunk shows
+/// whether it starts a line ending.
+fn normalize_newlines(data: &[u8], pending_cr: &mut bool) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len() + 1);
+    if *pending_cr && data.first() != Some(&b'\n') {
+        result.push(b'\r');
+    }
+    *pending_cr = false;
+    for (i, &byte) in data.iter().enumerate() {
+        match (byte, data.get(i + 1)) {
+            (b'\r', Some(b'\n')) => {}
+            (b'\r', None) => *pending_cr = true,
+            _ => result.push(byte),
+        }
+    }
+    result
+}
+
+async fn oneshot_request<
     T: Sink<Message> + Unpin,
     U: Stream<Item = Result<Message, TsError>> + Unpin,
-    X: AsyncRead + Unpin,
-    Y: AsyncWrite + Unpin,
 >(
+    request: Request,
     wsout: &mut T,
     wsin: &mut U,
-    mut pipein: X,
-    mut pipeout: Y,
-    echo: bool,
-) -> Result<Vec<String>, String>
+) -> Result<Reply, RtalError>
 where
     <T as Sink<Message>>::Error: Display,
 {
-    let mut closing = false;
-    let mut buffer = [0; BUFFER_SIZE];
-    let mut last_print_client = None;
-    let client_ended = Request::ConnectStop {};
-    let client_ended = match client_ended.forge() {
-        Ok(x) => x,
-        Err(x) => return Err(format!("Cannot forge request: {}", x)),
+    let request = match Request::forge(&request) {
+        Ok(x) => Message::Text(x),
+        Err(x) => return Err(RtalError::Io(format!("Cannot forge request: {}", x))),
+    };
+    if let Err(x) = wsout.send(request).await {
+        return Err(RtalError::PeerClosed(format!("Cannot send request: {}", x)));
     };
     loop {
-        select! {
-            msg = wsin.next() => {
-                match msg {
-                    Some(Ok(Message::Binary(x))) if !closing => {
-                        if echo {
-                            match last_print_client {
-                                None | Some(true) => {
-                                    println!("[SERVER]");
-                                    last_print_client = Some(false);
-                                }
-                                _ => {}
-                            }
-                            print!("{}", String::from_utf8_lossy(&x));
-                        }
-                        let mut close = false;
-                        if let Err(x) = pipeout.write_all(&x).await {
-                            warn!("Cannot write to user: {}", x);
-                            close = true;
-                        }
-                        if let Err(x) = pipeout.flush().await {
-                            warn!("Cannot flush stream: {}", x);
-                            close = true;
-                        }
-                        if close {
-                            if let Err(x) = wsout.send(Message::Text(client_ended.clone())).await {
-                                break Err(format!("Cannot send data to server: {}", x));
-                            }
-                            closing = true;
-                        }
-                    }
-                    Some(Ok(Message::Text(x))) => match Reply::parse(&x) {
-                        Ok(Reply::ConnectStop { status }) => {
-                            if !closing {
-                                if let Err(x) = wsout.send(Message::Text(client_ended.clone())).await {
-                                    break Err(format!("Cannot send data to server: {}", x));
-                                }
-                            }
-                            break status;
-                        }
-                        Ok(_) => break Err(format!("Received wrong message from server")),
-                        Err(x) => break Err(format!("Cannot parse server reply: {}", x)),
+        if let Some(msg) = wsin.next().await {
+            match msg {
+                Ok(Message::Text(x)) => match Reply::parse(&x) {
+                    Ok(x) => break Ok(x),
+                    Err(x) => {
+                        break Err(RtalError::Server(format!(
+                            "Could not parse server reply: {}",
+                            x
+                        )))
                     }
-                    Some(Ok(_)) => continue,
-                    Some(Err(x)) => break Err(format!("Connection lost: {}", x)),
-                    None => break Err(format!("Connection lost")),
-                };
+                },
+                Err(x) => {
+                    break Err(RtalError::PeerClosed(format!(
+                        "Connection lost while waiting for reply: {}",
+                        x
+                    )))
+                }
+                Ok(_) => {}
             }
-            size = pipein.read(&mut buffer), if !closing => {
-                let size = match size {
-                    Ok(0) => {
-                        if let Err(x) = wsout.send(Message::Text(client_ended.clone())).await {
-                            break Err(format!("Cannot send data to server: {}", x));
-                        }
-                        closing = true;
-                        continue;
-                    }
-                    Ok(x) => x,
+        } else {
+            break Err(RtalError::PeerClosed(
+                "Connection lost while waiting for reply".to_string(),
+            ));
+        }
+    }
+}
+
+async fn oneshot_reply<U: Stream<Item = Result<Message, TsError>> + Unpin>(
+    wsin: &mut U,
+) -> Result<Reply, RtalError> {
+    loop {
+        if let Some(msg) = wsin.next().await {
+            match msg {
+                Ok(Message::Text(x)) => match Reply::parse(&x) {
+                    Ok(x) => break Ok(x),
                     Err(x) => {
-                        warn!("Cannot read from user: {}", x);
-                        if let Err(x) = wsout.send(Message::Text(client_ended.clone())).await {
-                            break Err(format!("Cannot send data to server: {}", x));
-                        }
-                        closing = true;
-                        continue;
+                        break Err(RtalError::Server(format!(
+                            "Could not parse server reply: {}",
+                            x
+                        )))
                     }
-                };
-                if echo {
-                    match last_print_client {
-                        None | Some(false) => {
-                            println!("[CLIENT]");
-                            last_print_client = Some(true);
+                },
+                Err(x) => {
+                    break Err(RtalError::PeerClosed(format!(
+                        "Connection lost while waiting for reply: {}",
+                        x
+                    )))
+                }
+                Ok(_) => {}
+            }
+        } else {
+            break Err(RtalError::PeerClosed(
+                "Connection lost while waiting for reply".to_string(),
+            ));
+        }
+    }
+}
+
+fn tls_config(min_tls: &TlsVersion) -> Result<ClientConfig, RtalError> {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|x| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(x.subject, x.spki, x.name_constraints)
+    }));
+    let versions: &[&SupportedProtocolVersion] = match min_tls {
+        TlsVersion::Tls12 => &[&TLS13, &TLS12],
+        TlsVersion::Tls13 => &[&TLS13],
+    };
+    match ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)
+    {
+        Ok(x) => Ok(x.with_root_certificates(roots).with_no_client_auth()),
+        Err(x) => Err(RtalError::SocketConfig(format!(
+            "Cannot configure TLS: {}",
+            x
+        ))),
+    }
+}
+
+/// Prints the problems of `list`, stopping at the first error on `out`.
+fn print_problems(
+    out: &mut impl std::io::Write,
+    meta: HashMap<String, problem::Meta>,
+    filter: Option<&Regex>,
+    verbose: u8,
+) -> std::io::Result<()> {
+    let meta: BTreeMap<_, _> = meta.into_iter().collect();
+    for (name, meta) in meta.into_iter() {
+        if let Some(filter) = &filter {
+            if !filter.is_match(&name) {
+                continue;
+            }
+        }
+        writeln!(out, "- {}", name)?;
+        if verbose >= 1 {
+            let services: BTreeMap<_, _> = meta.services.into_iter().collect();
+            for (servname, servargs) in services.into_iter() {
+                writeln!(out, "  * {}", servname)?;
+                if let Some(args) = servargs.args {
+                    let args: BTreeMap<_, _> = args.into_iter().collect();
+                    for (argname, arg) in args.into_iter() {
+                        if verbose >= 2 {
+                            if let Some(def) = arg.default {
+                                writeln!(out, "    # {} [{}] {}", argname, def, arg.regex)?;
+                            } else {
+                                writeln!(out, "    # {} {}", argname, arg.regex)?;
+                            }
+                        } else {
+                            if let Some(def) = arg.default {
+                                writeln!(out, "    # {} [{}]", argname, def)?;
+                            } else {
+                                writeln!(out, "    # {}", argname)?;
+                            }
                         }
-                        _ => {}
                     }
-                    print!("{}", String::from_utf8_lossy(&buffer[..size]));
                 }
-                if let Err(x) = wsout.send(Message::Binary(buffer[..size].into())).await {
-                    break Err(format!("Cannot send data to server: {}", x));
+                if let Some(mut args) = servargs.files {
+                    args.sort();
+                    for arg in args.into_iter() {
+                        writeln!(out, "    § {}", arg)?;
+                    }
                 }
             }
         }
     }
+    Ok(())
+}
+
+/// Prints the upgrade request as tungstenite sends it, the key is random for each handshake.
+fn print_request(
+    out: &mut impl std::io::Write,
+    request: &ClientRequest,
+    mode: &PrintRequest,
+) -> std::io::Result<()> {
+    let uri = request.uri();
+    writeln!(
+        out,
+        "GET {} {:?}",
+        uri.path_and_query().map_or("/", |x| x.as_str()),
+        request.version()
+    )?;
+    for (name, value) in request.headers() {
+        let secret = ["authorization", "proxy-authorization", "cookie"].contains(&name.as_str());
+        if secret && *mode == PrintRequest::Redacted {
+            writeln!(out, "{}: <redacted>", name)?;
+        } else {
+            writeln!(out, "{}: {}", name, value.to_str().unwrap_or("<binary>"))?;
+        }
+    }
+    writeln!(out)
+}
+
+/// Prints what the handshake settled on, the limits are the ones this client enforces.
+fn print_negotiated(ws: &WebSocketStream<MaybeTlsStream<TcpStream>>, response: &ClientResponse) {
+    let header = |name: &str| {
+        let values: Vec<_> = response
+            .headers()
+            .get_all(name)
+            .iter()
+            .map(|x| x.to_str().unwrap_or("<binary>"))
+            .collect();
+        if values.is_empty() {
+            "none".to_string()
+        } else {
+            values.join(", ")
+        }
+    };
+    let limit = |x: Option<usize>| match x {
+        Some(x) => format!("{} bytes", x),
+        None => "unlimited".to_string(),
+    };
+    println!("> Extensions: {}", header("sec-websocket-extensions"));
+    println!("> Subprotocol: {}", header("sec-websocket-protocol"));
+    if let MaybeTlsStream::Rustls(x) = ws.get_ref() {
+        let tls = x.get_ref().1;
+        if let Some(version) = tls.protocol_version() {
+            println!("> TLS version: {:?}", version);
+        }
+        if let Some(suite) = tls.negotiated_cipher_suite() {
+            println!("> TLS cipher suite: {:?}", suite.suite());
+        }
+        if let Some(alpn) = tls.alpn_protocol() {
+            println!("> TLS ALPN: {}", String::from_utf8_lossy(alpn));
+        }
+    }
+    let config = ws.get_config();
+    println!("> Max message size: {}", limit(config.max_message_size));
+    println!("> Max frame size: {}", limit(config.max_frame_size));
+    println!("> Max send queue: {}", limit(config.max_send_queue));
+}
+
+/// Connects to the first reachable address of `target`, sizing the socket buffers beforehand.
+async fn connect_tcp(
+    target: &str,
+    sndbuf: Option<u32>,
+    rcvbuf: Option<u32>,
+) -> std::io::Result<TcpStream> {
+    let mut last_error = None;
+    for address in lookup_host(target).await? {
+        let socket = if address.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        if let Some(x) = sndbuf {
+            socket.set_send_buffer_size(x)?;
+        }
+        if let Some(x) = rcvbuf {
+            socket.set_recv_buffer_size(x)?;
+        }
+        if sndbuf.is_some() || rcvbuf.is_some() {
+            // The OS is free to clamp or round the requested sizes
+            info!(
+                "Socket buffers granted: send {} bytes, receive {} bytes",
+                socket.send_buffer_size()?,
+                socket.recv_buffer_size()?
+            );
+        }
+        match socket.connect(address).await {
+            Ok(x) => return Ok(x),
+            Err(x) => last_error = Some(x),
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no address found")))
+}
+
+/// Dials the server by itself, so that the TCP target and the TLS name can differ from the
+/// URL host, which is still the one sent in the `Host` header.
+async fn connect_manually(
+    request: ClientRequest,
+    args: &CliArgs,
+    config: ClientConfig,
+) -> Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, ClientResponse), RtalError> {
+    let connect_to = args.connect_to.as_deref();
+    let sni = args.tls_sni.as_deref();
+    let uri = request.uri().clone();
+    let secure = uri.scheme_str() == Some("wss");
+    if sni.is_some() && !secure {
+        return Err(RtalError::SocketConfig(format!(
+            "--tls-sni requires a wss:// server URL, got \"{}\"",
+            uri
+        )));
+    }
+    let host = uri.host().unwrap_or_default();
+    let port = uri.port_u16().unwrap_or(if secure { 443 } else { 80 });
+    let target = match connect_to {
+        Some(x) => x.to_string(),
+        None => format!("{}:{}", host, port),
+    };
+    let stream = match connect_tcp(&target, args.so_sndbuf, args.so_rcvbuf).await {
+        Ok(x) => x,
+        Err(x) => {
+            return Err(RtalError::SocketConfig(format!(
+                "Cannot connect to \"{}\": {}",
+                target, x
+            )))
+        }
+    };
+    if !secure {
+        return match client_async(request, MaybeTlsStream::Plain(stream)).await {
+            Ok(x) => Ok(x),
+            Err(x) => Err(RtalError::SocketConfig(format!(
+                "Cannot connect to \"{}\": {}",
+                uri, x
+            ))),
+        };
+    }
+    let sni = sni.unwrap_or_else(|| host.trim_start_matches('[').trim_end_matches(']'));
+    let name = match ServerName::try_from(sni) {
+        Ok(x) => x,
+        Err(x) => {
+            return Err(RtalError::SocketConfig(format!(
+                "Invalid TLS name \"{}\": {}",
+                sni, x
+            )))
+        }
+    };
+    let stream = match TlsConnector::from(Arc::new(config))
+        .connect(name, stream)
+        .await
+    {
+        Ok(x) => x,
+        Err(x) => {
+            return Err(RtalError::SocketConfig(format!(
+                "TLS handshake with \"{}\" as \"{}\" failed: {}",
+                target, sni, x
+            )))
+        }
+    };
+    match client_async(request, MaybeTlsStream::Rustls(stream)).await {
+        Ok(x) => Ok(x),
+        Err(x) => Err(RtalError::SocketConfig(format!(
+            "Cannot connect to \"{}\": {}",
+            uri, x
+        ))),
+    }
+}
+
+async fn dial(
+    server_url: &str,
+    args: &CliArgs,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, RtalError> {
+    let request = match server_url.into_client_request() {
+        Ok(x) => x,
+        Err(x) => {
+            return Err(RtalError::SocketConfig(format!(
+                "Invalid server URL \"{}\": {}",
+                server_url, x
+            )))
+        }
+    };
+    if let Some(ref mode) = args.print_request {
+        match print_request(&mut std::io::stdout().lock(), &request, mode) {
+            Ok(()) => {}
+            Err(x) if x.kind() == std::io::ErrorKind::BrokenPipe => {}
+            Err(x) => return Err(RtalError::Io(format!("Cannot print the request: {}", x))),
+        }
+    }
+    let dial_manually = args.tls_sni.is_some()
+        || args.connect_to.is_some()
+        || args.so_sndbuf.is_some()
+        || args.so_rcvbuf.is_some();
+    let (mut ws, response) = if dial_manually {
+        let config = tls_config(args.min_tls.as_ref().unwrap_or(&TlsVersion::Tls12))?;
+        connect_manually(request, args, config).await?
+    } else {
+        let connector = match args.min_tls {
+            Some(ref min_tls) => Some(Connector::Rustls(Arc::new(tls_config(min_tls)?))),
+            None => None,
+        };
+        match connect_async_tls_with_config(request, None, connector).await {
+            Ok(x) => x,
+            Err(x) => {
+                return Err(RtalError::SocketConfig(format!(
+                    "Cannot connect to \"{}\": {}",
+                    server_url, x
+                )))
+            }
+        }
+    };
+    let result = match ws.get_mut() {
+        &mut MaybeTlsStream::Plain(ref mut x) => x.set_nodelay(true),
+        &mut MaybeTlsStream::Rustls(ref mut x) => x.get_mut().0.set_nodelay(true),
+        &mut _ => unreachable!("Using stream other than Plain or Rustls"),
+    };
+    if let Err(x) = result {
+        warn!("Cannot set TCP_NODELAY: {}", x);
+    }
+    if let Some(ref min_tls) = args.min_tls {
+        let version = match ws.get_ref() {
+            MaybeTlsStream::Rustls(x) => x.get_ref().1.protocol_version(),
+            _ => None,
+        };
+        let floor = match min_tls {
+            TlsVersion::Tls12 => ProtocolVersion::TLSv1_2,
+            TlsVersion::Tls13 => ProtocolVersion::TLSv1_3,
+        };
+        match version {
+            Some(x) if x.get_u16() >= floor.get_u16() => info!("Negotiated {:?}", x),
+            Some(x) => {
+                return Err(RtalError::SocketConfig(format!(
+                    "Server negotiated {:?}, below the required {:?}",
+                    x, floor
+                )))
+            }
+            None => {
+                return Err(RtalError::SocketConfig(format!(
+                    "\"{}\" is not a TLS connection",
+                    server_url
+                )))
+            }
+        }
+    }
+    if args.print_negotiated {
+        print_negotiated(&ws, &response);
+    }
+    if let Some(ref wanted) = args.require_server_version {
+        let version = response
+            .headers()
+            .get(proto::VERSION_HEADER)
+            .and_then(|x| x.to_str().ok());
+        match version.map(|x| (x, Version::parse(x))) {
+            Some((_, Ok(x))) if wanted.matches(&x) => info!("Server version {}", x),
+            Some((x, Ok(_))) => {
+                return Err(RtalError::Handshake(format!(
+                    "Server version {} does not satisfy {}",
+                    x, wanted
+                )))
+            }
+            Some((x, Err(y))) => {
+                return Err(RtalError::Handshake(format!(
+                    "Server sent an invalid version \"{}\": {}",
+                    x, y
+                )))
+            }
+            None => {
+                return Err(RtalError::Handshake(format!(
+                    "Server did not tell its version, {} is required",
+                    wanted
+                )))
+            }
+        }
+    }
+    Ok(ws)
 }
 
-async fn oneshot_request<
+/// Replies to the nonce sent by the server with its HMAC-SHA256 under the shared key.
+async fn authenticate<
     T: Sink<Message> + Unpin,
     U: Stream<Item = Result<Message, TsError>> + Unpin,
 >(
-    request: Request,
     wsout: &mut T,
     wsin: &mut U,
-) -> Result<Reply, String>
+    key_path: &Path,
+) -> Result<(), RtalError>
 where
     <T as Sink<Message>>::Error: Display,
 {
-    let request = match Request::forge(&request) {
-        Ok(x) => Message::Text(x),
-        Err(x) => return Err(format!("Cannot forge request: {}", x)),
+    let key = match tokio::fs::read(key_path).await {
+        Ok(x) => x,
+        Err(x) => {
+            return Err(RtalError::Io(format!(
+                "Cannot read \"{}\": {}",
+                key_path.display(),
+                x
+            )))
+        }
     };
-    if let Err(x) = wsout.send(request).await {
-        return Err(format!("Cannot send request: {}", x));
+    let key = key.strip_suffix(b"\n").unwrap_or(&key[..]);
+    let challenge = loop {
+        match wsin.next().await {
+            Some(Ok(Message::Text(x))) => break x.into_bytes(),
+            Some(Ok(Message::Binary(x))) => break x,
+            Some(Ok(_)) => continue,
+            Some(Err(x)) => {
+                return Err(RtalError::Auth(format!(
+                    "Connection lost while waiting for the authentication challenge: {}",
+                    x
+                )))
+            }
+            None => {
+                return Err(RtalError::Auth(
+                    "Connection lost while waiting for the authentication challenge".to_string(),
+                ))
+            }
+        }
+    };
+    let mut mac = match Hmac::<Sha256>::new_from_slice(key) {
+        Ok(x) => x,
+        Err(x) => {
+            return Err(RtalError::Auth(format!(
+                "Invalid authentication key: {}",
+                x
+            )))
+        }
+    };
+    mac.update(&challenge);
+    let response = format!("{:x}", mac.finalize().into_bytes());
+    if let Err(x) = wsout.send(Message::Text(response)).await {
+        return Err(RtalError::Auth(format!(
+            "Cannot send the authentication response: {}",
+            x
+        )));
+    }
+    Ok(())
+}
+
+/// Splits a `connect ws[s]://host[/prefix]/problem/service?arg=value` shorthand into the
+/// server URL, problem, service and arguments. Values given explicitly on the command line take
+/// precedence.
+fn expand_shorthand(mut args: CliArgs) -> Result<CliArgs, RtalError> {
+    if let Command::Connect(ref mut connect) = args.command {
+        let ConnectArgs {
+            ref mut problem,
+            ref mut service,
+            ref mut service_arg,
+            ..
+        } = **connect;
+        if !(problem.starts_with("ws://") || problem.starts_with("wss://")) {
+            return Ok(args);
+        }
+        let malformed = || {
+            RtalError::Usage(format!(
+                "\"{}\" is not of the form ws[s]://host[/prefix]/problem[/service][?arg=value&...]",
+                problem
+            ))
+        };
+        let url = Url::parse(problem.as_str()).map_err(|_| malformed())?;
+        let segments: Vec<String> = match url.path_segments() {
+            Some(x) => x.filter(|x| !x.is_empty()).map(String::from).collect(),
+            None => Vec::new(),
+        };
+        // With more than one segment the last two are the problem and the service, what comes
+        // before them is the path of the server behind e.g. a reverse proxy
+        let (prefix, name, wanted) = match &segments[..] {
+            [] => return Err(malformed()),
+            [x] => (&[][..], x.clone(), None),
+            [prefix @ .., x, y] => (prefix, x.clone(), Some(y.clone())),
+        };
+        let mut parsed: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect();
+        if parsed.iter().any(|(k, _)| k.is_empty()) {
+            return Err(malformed());
+        }
+        parsed.append(service_arg);
+        *service_arg = parsed;
+        if service.is_none() {
+            *service = wanted;
+        }
+        if args.server_url.is_none() {
+            let mut base = url.clone();
+            base.set_path(&format!("/{}", prefix.join("/")));
+            base.set_query(None);
+            args.server_url = Some(base.to_string());
+        }
+        *problem = name;
+    }
+    Ok(args)
+}
+
+async fn start(args: CliArgs, ask_to_exit: &mut bool) -> Result<(), RtalError> {
+    let args = expand_shorthand(args)?;
+    let metrics_file = args.metrics_file.clone();
+    let (problem, service, watch) = match args.command {
+        Command::Connect(ref x) => (
+            x.problem.clone(),
+            x.service
+                .clone()
+                .unwrap_or_else(|| DEFAULT_SERVICE.to_string()),
+            x.watch.clone(),
+        ),
+        _ => (String::new(), String::new(), None),
     };
     loop {
-        if let Some(msg) = wsin.next().await {
-            match msg {
-                Ok(Message::Text(x)) => match Reply::parse(&x) {
-                    Ok(x) => break Ok(x),
-                    Err(x) => break Err(format!("Could not parse server reply: {}", x)),
-                },
-                Err(x) => break Err(format!("Connection lost while waiting for reply: {}", x)),
-                Ok(_) => {}
+        // Taken before the session, so that an edit saved while it runs is not missed
+        let initial = match watch {
+            Some(ref x) => modified(x).await,
+            None => None,
+        };
+        let mut metrics = Metrics::default();
+        let result = session(args.clone(), ask_to_exit, &mut metrics).await;
+        if let Some(ref path) = metrics_file {
+            let snapshot = metrics.render(&problem, &service, result.is_ok());
+            if let Err(x) = tokio::fs::write(path, snapshot).await {
+                warn!("Cannot write metrics to {:?}: {}", path, x);
             }
-        } else {
-            break Err(format!("Connection lost while waiting for reply"));
         }
+        let path = match watch {
+            Some(ref x) if !matches!(result, Err(RtalError::Interrupted(_))) => x,
+            _ => return result,
+        };
+        if let Err(ref x) = result {
+            error!("{}", x);
+        }
+        println!("> Watching {:?} for changes, press Ctrl-C to stop", path);
+        if !wait_for_change(path, initial).await {
+            return result;
+        }
+        println!("> {:?} changed, running again", path);
     }
 }
 
-async fn oneshot_reply<U: Stream<Item = Result<Message, TsError>> + Unpin>(
-    wsin: &mut U,
-) -> Result<Reply, String> {
+async fn modified(path: &Path) -> Option<std::time::SystemTime> {
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|x| x.modified())
+        .ok()
+}
+
+/// Polls the modification time of `path` until it differs from `initial` and settles, false on
+/// Ctrl-C.
+async fn wait_for_change(path: &Path, initial: Option<std::time::SystemTime>) -> bool {
     loop {
-        if let Some(msg) = wsin.next().await {
-            match msg {
-                Ok(Message::Text(x)) => match Reply::parse(&x) {
-                    Ok(x) => break Ok(x),
-                    Err(x) => break Err(format!("Could not parse server reply: {}", x)),
-                },
-                Err(x) => break Err(format!("Connection lost while waiting for reply: {}", x)),
-                Ok(_) => {}
+        select! {
+            _ = sleep(Duration::from_secs_f64(WATCH_POLL)) => {}
+            _ = ctrl_c() => return false,
+        }
+        let mut last = modified(path).await;
+        if last == initial {
+            continue;
+        }
+        // Editors often save in several steps, wait for the file to stay still
+        loop {
+            select! {
+                _ = sleep(Duration::from_secs_f64(WATCH_DEBOUNCE)) => {}
+                _ = ctrl_c() => return false,
             }
-        } else {
-            break Err(format!("Connection lost while waiting for reply"));
+            let current = modified(path).await;
+            if current == last {
+                return true;
+            }
+            last = current;
         }
     }
 }
 
-async fn start(args: CliArgs, ask_to_exit: &mut bool) -> Result<(), String> {
-    let mut ws = match connect_async(&args.server_url).await {
-        Ok(x) => x.0,
-        Err(x) => return Err(format!("Cannot connect to \"{}\": {}", args.server_url, x)),
+async fn session(
+    args: CliArgs,
+    ask_to_exit: &mut bool,
+    metrics: &mut Metrics,
+) -> Result<(), RtalError> {
+    let server_url = args
+        .server_url
+        .clone()
+        .unwrap_or_else(|| DEFAULT_SERVER_URL.to_string());
+    let mut servers: Vec<&str> = server_url.split(',').map(str::trim).collect();
+    if args.random_server {
+        servers.shuffle(&mut rand::thread_rng());
+    }
+    let deadline = match args.command {
+        Command::Connect(ref x) => match x.deadline {
+            Some(ref x) => Some(deadline_instant(x)?),
+            None => None,
+        },
+        _ => None,
     };
-    let result = match ws.get_mut() {
-        &mut MaybeTlsStream::Plain(ref mut x) => x.set_nodelay(true),
-        &mut MaybeTlsStream::Rustls(ref mut x) => x.get_mut().0.set_nodelay(true),
-        &mut _ => unreachable!("Using stream other than Plain or Rustls"),
+    // The deadline also covers reaching the server and the handshake
+    let connecting = async {
+        let mut last_error = None;
+        let mut ws = None;
+        for server_url in servers {
+            let begin = Instant::now();
+            match dial(server_url, &args).await {
+                Ok(x) => {
+                    debug!("Connected to {}", server_url);
+                    metrics.server = Some(server_url.to_string());
+                    metrics.connect = Some(begin.elapsed());
+                    ws = Some(x);
+                    break;
+                }
+                Err(x) => {
+                    warn!("{}", x);
+                    last_error = Some(x);
+                }
+            }
+        }
+        let ws = match (ws, last_error) {
+            (Some(x), _) => x,
+            (None, Some(x)) => return Err(x),
+            (None, None) => return Err(RtalError::SocketConfig("No server URL given".to_string())),
+        };
+        let (mut wsout, mut wsin) = ws.split();
+        if let Some(ref path) = args.auth_key_file {
+            authenticate(&mut wsout, &mut wsin, path).await?;
+        }
+        let handshake_lost = |x: String| {
+            if args.auth_key_file.is_some() {
+                RtalError::Auth(format!(
+                    "{}, the server probably rejected the authentication",
+                    x
+                ))
+            } else {
+                RtalError::Handshake(x)
+            }
+        };
+        let handshake_request = match Request::forge(&Request::Handshake {
+            magic: proto::MAGIC.to_string(),
+            version: proto::VERSION,
+        }) {
+            Ok(x) => Message::Text(x),
+            Err(x) => {
+                return Err(RtalError::Handshake(format!(
+                    "Cannot forge handshake request: {}",
+                    x
+                )))
+            }
+        };
+        if let Err(x) = wsout.send(handshake_request).await {
+            return Err(RtalError::Handshake(format!(
+                "Cannot send handshake request: {}",
+                x
+            )));
+        };
+        let handshake_reply = loop {
+            if let Some(msg) = wsin.next().await {
+                match msg {
+                    Ok(Message::Text(x)) => match Reply::parse(&x) {
+                        Ok(Reply::Handshake { magic, version }) => break (magic, version),
+                        Ok(_) => {
+                            return Err(RtalError::Handshake(
+                                "Server performed a wrong handshake".to_string(),
+                            ))
+                        }
+                        Err(x) => {
+                            return Err(RtalError::Handshake(format!(
+                                "Could not parse server handshake: {}",
+                                x
+                            )))
+                        }
+                    },
+                    Err(x) => {
+                        return Err(handshake_lost(format!(
+                            "Connection lost while performing handshake: {}",
+                            x
+                        )))
+                    }
+                    Ok(_) => {}
+                }
+            } else {
+                return Err(handshake_lost(
+                    "Connection lost while performing handshake".to_string(),
+                ));
+            }
+        };
+        if !(handshake_reply.0 == proto::MAGIC && handshake_reply.1 == proto::VERSION) {
+            return if handshake_reply.0 == proto::MAGIC {
+                Err(RtalError::Handshake(format!(
+                    "Protocol version mismatch: local={}, server={}",
+                    proto::VERSION,
+                    handshake_reply.1
+                )))
+            } else {
+                Err(RtalError::Handshake(format!(
+                    "\"{}\" is not a Turing Arena Light server",
+                    server_url
+                )))
+            };
+        }
+        Ok::<_, RtalError>((wsout, wsin))
+    };
+    let (mut wsout, mut wsin) = match deadline {
+        Some(x) => match timeout_at(x, connecting).await {
+            Ok(x) => x?,
+            Err(_) => {
+                return Err(RtalError::Deadline(
+                    "The session deadline has been reached".to_string(),
+                ))
+            }
+        },
+        None => connecting.await?,
+    };
+    // With --forward-signals Ctrl-C belongs to the program
+    let catch_interrupt = !matches!(
+        args.command,
+        Command::Connect(ref x) if x.forward_signals
+    );
+    let colored = match args.command {
+        Command::Connect(ref x) => x.color.enabled(),
+        _ => Color::Auto.enabled(),
+    };
+    let result = select! {
+        x = args.command.run(&mut wsout, &mut wsin, ask_to_exit, &mut metrics.stats) => x,
+        _ = interrupted(catch_interrupt) => Err(RtalError::Interrupted("Interrupted by the user".to_string())),
+        // The session itself stops at the deadline, this cuts short whatever comes around it
+        _ = sleep_until(deadline.unwrap_or_else(TokioInstant::now) + Duration::from_secs_f64(DEADLINE_SLACK)), if deadline.is_some() => {
+            Err(RtalError::Deadline("The session deadline has been reached".to_string()))
+        }
+    };
+    let close = match result {
+        Ok(()) => &args.close_normal,
+        Err(RtalError::Interrupted(_)) => &args.close_interrupt,
+        Err(RtalError::Timeout(_)) | Err(RtalError::Deadline(_)) => &args.close_timeout,
+        Err(_) => &args.close_error,
+    };
+    let mut ws = match wsin.reunite(wsout) {
+        Ok(x) => x,
+        Err(x) => return Err(RtalError::Io(format!("Cannot reunite streams {}", x))),
+    };
+    let frame = CloseFrame {
+        code: CloseCode::from(close.code),
+        reason: close.reason.clone().into(),
     };
+    match ws.close(Some(frame)).await {
+        Ok(()) | Err(TsError::ConnectionClosed) | Err(TsError::AlreadyClosed) => {}
+        // The session error has already been reported, the connection is likely gone
+        Err(x) if result.is_err() => debug!("Could not close connection to server: {}", x),
+        Err(x) => warn!("Could not close connection to server gracefully: {}", x),
+    }
+    if args.color_verdict {
+        print_outcome(&result, close, colored);
+    }
+    result
+}
+
+/// Converts `--deadline` to an instant, failing if it has already passed.
+fn deadline_instant(deadline: &DateTime<FixedOffset>) -> Result<TokioInstant, RtalError> {
+    match (deadline.with_timezone(&Utc) - Utc::now()).to_std() {
+        Ok(remaining) => Ok(TokioInstant::now() + remaining),
+        Err(_) => Err(RtalError::Deadline(format!(
+            "The deadline {} has already passed",
+            deadline.to_rfc3339()
+        ))),
+    }
+}
+
+/// Prints the one line summary of how the session ended for `--color-verdict`.
+fn print_outcome(result: &Result<(), RtalError>, close: &CloseSpec, colored: bool) {
+    let (label, color) = match result {
+        Ok(()) => ("OK", "\x1b[1;32m"),
+        Err(RtalError::Interrupted(_)) => ("INTERRUPTED", "\x1b[1;33m"),
+        Err(RtalError::Timeout(_)) | Err(RtalError::Deadline(_)) => ("TIMEOUT", "\x1b[1;33m"),
+        Err(_) => ("ERROR", "\x1b[1;31m"),
+    };
+    let mut line = if colored {
+        format!("{}{}\x1b[0m", color, label)
+    } else {
+        label.to_string()
+    };
+    line.push_str(&format!(" close: {}", close.code));
+    if !close.reason.is_empty() {
+        line.push_str(&format!(" ({})", close.reason));
+    }
     if let Err(x) = result {
-        warn!("Cannot set TCP_NODELAY: {}", x);
+        line.push_str(&format!(" - {}", x));
+    }
+    println!("{}", line);
+}
+
+/// Waits for the client of `rtal relay` and completes its WebSocket upgrade.
+async fn accept_client(listen: &str) -> Result<WebSocketStream<TcpStream>, RtalError> {
+    let listener = match TcpListener::bind(listen).await {
+        Ok(x) => x,
+        Err(x) => {
+            return Err(RtalError::SocketConfig(format!(
+                "Cannot listen on {}: {}",
+                listen, x
+            )))
+        }
+    };
+    println!("> Waiting for a client on {}", listen);
+    let (stream, peer) = match listener.accept().await {
+        Ok(x) => x,
+        Err(x) => return Err(RtalError::Io(format!("Cannot accept client: {}", x))),
+    };
+    match accept_async(stream).await {
+        Ok(x) => {
+            println!("> Relaying {}", peer);
+            Ok(x)
+        }
+        Err(x) => Err(RtalError::Handshake(format!(
+            "Cannot upgrade connection from {}: {}",
+            peer, x
+        ))),
     }
-    let (mut wsout, mut wsin) = ws.split();
-    let handshake_request = match Request::forge(&Request::Handshake {
+}
+
+/// Forwards messages both ways until either side closes, pings are answered on each leg. The
+/// server went through the handshake already, so the one of the client is answered here.
+async fn relay<T: Sink<Message> + Unpin, U: Stream<Item = Result<Message, TsError>> + Unpin>(
+    client: WebSocketStream<TcpStream>,
+    wsout: &mut T,
+    wsin: &mut U,
+    log: &mut SessionLog,
+    stats: &mut SessionStats,
+) -> Result<(), RtalError>
+where
+    <T as Sink<Message>>::Error: Display,
+{
+    let handshake = match Reply::forge(&Reply::Handshake {
         magic: proto::MAGIC.to_string(),
         version: proto::VERSION,
     }) {
-        Ok(x) => Message::Text(x),
-        Err(x) => return Err(format!("Cannot forge handshake request: {}", x)),
-    };
-    if let Err(x) = wsout.send(handshake_request).await {
-        return Err(format!("Cannot send handshake request: {}", x));
+        Ok(x) => x,
+        Err(x) => {
+            return Err(RtalError::Io(format!(
+                "Cannot forge handshake reply: {}",
+                x
+            )))
+        }
     };
-    let handshake_reply = loop {
-        if let Some(msg) = wsin.next().await {
-            match msg {
-                Ok(Message::Text(x)) => match Reply::parse(&x) {
-                    Ok(Reply::Handshake { magic, version }) => break (magic, version),
-                    Ok(_) => return Err(format!("Server performed a wrong handshake")),
-                    Err(x) => return Err(format!("Could not parse server handshake: {}", x)),
-                },
-                Err(x) => return Err(format!("Connection lost while performing handshake: {}", x)),
-                Ok(_) => {}
+    let (mut client_out, mut client_in) = client.split();
+    let mut seq = 0;
+    loop {
+        let (msg, direction) = select! {
+            x = client_in.next() => (x, "C>S"),
+            x = wsin.next() => (x, "S>C"),
+        };
+        let msg = match (msg, direction) {
+            (Some(Ok(x)), _) => x,
+            (Some(Err(x)), "C>S") => {
+                warn!("Connection with the client lost: {}", x);
+                return Ok(());
             }
-        } else {
-            return Err(format!("Connection lost while performing handshake"));
+            (Some(Err(x)), _) => {
+                return Err(RtalError::PeerClosed(format!("Connection lost: {}", x)))
+            }
+            (None, "C>S") => return Ok(()),
+            (None, _) => return Err(RtalError::PeerClosed("Connection lost".to_string())),
+        };
+        let data = match msg {
+            Message::Text(ref x) => x.as_bytes(),
+            Message::Binary(ref x) => &x[..],
+            // session() closes the server leg once the client is gone
+            Message::Close(_) if direction == "C>S" => return Ok(()),
+            Message::Close(_) => {
+                drop(client_out.close().await);
+                return Err(RtalError::PeerClosed(
+                    "The server closed the connection".to_string(),
+                ));
+            }
+            _ => continue,
+        };
+        seq += 1;
+        println!(
+            "{} {} {} \"{}\"",
+            seq,
+            direction,
+            data.len(),
+            String::from_utf8_lossy(data).escape_debug()
+        );
+        log.record(seq, direction, data).await;
+        if direction == "S>C" {
+            stats.received.record(data.len());
+            if let Err(x) = client_out.send(msg).await {
+                warn!("Cannot relay message to the client: {}", x);
+                return Ok(());
+            }
+            continue;
         }
-    };
-    if !(handshake_reply.0 == proto::MAGIC && handshake_reply.1 == proto::VERSION) {
-        return if handshake_reply.0 == proto::MAGIC {
-            Err(format!(
-                "Protocol version mismatch: local={}, server={}",
-                proto::VERSION,
-                handshake_reply.1
-            ))
-        } else {
-            Err(format!(
-                "\"{}\" is not a Turing Arena Light server",
-                args.server_url
-            ))
+        stats.sent.record(data.len());
+        let handshake_request = match msg {
+            Message::Text(ref x) => matches!(Request::parse(x), Ok(Request::Handshake { .. })),
+            _ => false,
         };
+        if handshake_request {
+            if let Err(x) = client_out.send(Message::Text(handshake.clone())).await {
+                warn!("Cannot answer the client handshake: {}", x);
+                return Ok(());
+            }
+        } else if let Err(x) = wsout.send(msg).await {
+            return Err(RtalError::PeerClosed(format!(
+                "Cannot relay message: {}",
+                x
+            )));
+        }
     }
-    args.command.run(&mut wsout, &mut wsin, ask_to_exit).await?;
-    ws = match wsin.reunite(wsout) {
-        Ok(x) => x,
-        Err(x) => return Err(format!("Cannot reunite streams {}", x)),
-    };
-    match ws.close(None).await {
-        Ok(()) | Err(TsError::ConnectionClosed) => {}
-        Err(x) => warn!("Could not close connection to server gracefully: {}", x),
+}
+
+/// Resolves on Ctrl-C when `enabled`, never otherwise.
+async fn interrupted(enabled: bool) {
+    if !enabled || ctrl_c().await.is_err() {
+        std::future::pending::<()>().await;
     }
-    Ok(())
 }
 
 fn init_logging() {
+    // A plain level such as RUST_LOG=debug, per target filters need the env-filter feature
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|x| x.parse::<tracing::Level>().ok())
+        .unwrap_or(tracing::Level::INFO);
     if let Err(x) = tracing_subscriber::fmt()
         .event_format(
             tracing_subscriber::fmt::format()
                 .without_time()
                 .with_target(false),
         )
-        //.with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_max_level(level)
         .try_init()
     {
         println!("Cannot enable logging service: {}", x);
@@ -686,17 +4637,657 @@ fn init_logging() {
 fn main() {
     init_logging();
     let args = CliArgs::parse();
-    match Runtime::new() {
+    let code = match Runtime::new() {
         Ok(rt) => rt.block_on(async move {
             let mut ask_to_exit = false;
-            match start(args, &mut ask_to_exit).await {
-                Ok(()) => {}
-                Err(x) => error!("{}", x),
-            }
+            let code = match start(args, &mut ask_to_exit).await {
+                Ok(()) => 0,
+                Err(x) => {
+                    error!("{}", x);
+                    x.exit_code()
+                }
+            };
             if ask_to_exit {
                 println!("[Press ENTER to exit]");
             }
+            code
         }),
-        Err(x) => error!("Cannot create tokio runtime: {}", x),
+        Err(x) => {
+            error!("Cannot create tokio runtime: {}", x);
+            1
+        }
     };
+    std::process::exit(code);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_output_stays_inside_base() {
+        let base = Path::new("out");
+        assert_eq!(
+            expand_output("{problem}/{name}", base, "sum", "input.txt"),
+            Ok(PathBuf::from("out/sum/input.txt"))
+        );
+        assert_eq!(
+            expand_output("./{name}", base, "sum", "data/input.txt"),
+            Ok(PathBuf::from("out/./data/input.txt"))
+        );
+        assert!(expand_output("{name}", base, "sum", "../input.txt").is_err());
+        assert!(expand_output("{name}", base, "sum", "data/../../input.txt").is_err());
+        assert!(expand_output("/tmp/{name}", base, "sum", "input.txt").is_err());
+        assert!(expand_output("{problem}", base, "..", "input.txt").is_err());
+    }
+
+    #[test]
+    fn parse_frame_marker_escapes() {
+        assert_eq!(&*parse_frame_marker("hex:0d0a").unwrap(), b"\r\n");
+        assert_eq!(&*parse_frame_marker("hex:").unwrap(), b"");
+        assert!(parse_frame_marker("hex:0d0").is_err());
+        assert!(parse_frame_marker("hex:zz").is_err());
+        assert_eq!(
+            &*parse_frame_marker(r"a\n\r\t\0\\\x41").unwrap(),
+            b"a\n\r\t\0\\A"
+        );
+        assert_eq!(&*parse_frame_marker("é").unwrap(), "é".as_bytes());
+        assert!(parse_frame_marker(r"\q").is_err());
+        assert!(parse_frame_marker(r"\x4").is_err());
+        assert!(parse_frame_marker("\\").is_err());
+    }
+
+    #[test]
+    fn close_spec_codes() {
+        let spec: CloseSpec = "4000:bye".parse().unwrap();
+        assert_eq!((spec.code, spec.reason.as_str()), (4000, "bye"));
+        let spec: CloseSpec = "1000".parse().unwrap();
+        assert_eq!((spec.code, spec.reason.as_str()), (1000, ""));
+        for code in ["1003", "1007", "1014", "3000", "4999"] {
+            assert!(code.parse::<CloseSpec>().is_ok(), "{}", code);
+        }
+        for code in ["999", "1004", "1005", "1006", "1015", "2000", "5000", "abc"] {
+            assert!(code.parse::<CloseSpec>().is_err(), "{}", code);
+        }
+        assert!(format!("4000:{}", "x".repeat(123))
+            .parse::<CloseSpec>()
+            .is_ok());
+        assert!(format!("4000:{}", "x".repeat(124))
+            .parse::<CloseSpec>()
+            .is_err());
+    }
+
+    #[test]
+    fn outbound_rules() {
+        let rules = OutboundRules {
+            max_size: Some(5),
+            prefix: Some(">".to_string()),
+            terminator: Some("\n".to_string()),
+            charset: Some(Charset::Ascii),
+        };
+        assert!(rules.check(b">ab\n").is_ok());
+        assert!(rules.check(b">abcde\n").is_err());
+        assert!(rules.check(b"ab\n").is_err());
+        assert!(rules.check(b">ab").is_err());
+        assert!(rules.check(">\u{e9}\n".as_bytes()).is_err());
+        let printable = OutboundRules {
+            charset: Some(Charset::Printable),
+            ..Default::default()
+        };
+        assert!(printable.check(b"a b\t\r\n").is_ok());
+        assert!(printable.check(b"a\x01").is_err());
+        let utf8 = OutboundRules {
+            charset: Some(Charset::Utf8),
+            ..Default::default()
+        };
+        assert!(utf8.check("\u{e9}".as_bytes()).is_ok());
+        assert!(utf8.check(b"\xc3").is_err());
+        assert!(OutboundRules::default().check(b"\xff").is_ok());
+    }
+
+    #[test]
+    fn normalize_newlines_across_chunks() {
+        let mut pending_cr = false;
+        assert_eq!(normalize_newlines(b"a\r\nb\r", &mut pending_cr), b"a\nb");
+        assert!(pending_cr);
+        assert_eq!(normalize_newlines(b"\nc\r", &mut pending_cr), b"\nc");
+        assert!(pending_cr);
+        assert_eq!(normalize_newlines(b"d", &mut pending_cr), b"\rd");
+        assert!(!pending_cr);
+        assert_eq!(normalize_newlines(b"e\rf", &mut pending_cr), b"e\rf");
+    }
+
+    #[test]
+    fn check_utf8_across_chunks() {
+        let mut tail = Vec::new();
+        let mut offset = 0;
+        assert_eq!(check_utf8(b"a\xc3", &mut tail, &mut offset), Ok(()));
+        assert_eq!((tail.as_slice(), offset), (&b"\xc3"[..], 1));
+        assert_eq!(check_utf8(b"\xa9b", &mut tail, &mut offset), Ok(()));
+        assert_eq!((tail.as_slice(), offset), (&b""[..], 4));
+        assert_eq!(check_utf8(b"cd\xff", &mut tail, &mut offset), Err(6));
+    }
+
+    #[test]
+    fn loop_watchdog() {
+        let mut watchdog = LoopWatchdog::new(2);
+        assert!(!watchdog.bounced(b"ping", false));
+        assert!(!watchdog.bounced(b"ping", true));
+        assert!(!watchdog.bounced(b"ping", false));
+        assert!(watchdog.bounced(b"ping", true));
+        let mut watchdog = LoopWatchdog::new(1);
+        assert!(!watchdog.bounced(b"ping", false));
+        assert!(!watchdog.bounced(b"ping", true));
+        assert!(!watchdog.bounced(b"pong", false));
+        assert!(!watchdog.bounced(b"pong", true));
+        assert!(!watchdog.bounced(b"ping", true));
+    }
+
+    #[test]
+    fn verdict_split_across_messages() {
+        let mut filter = VerdictFilter::new("result,score,message".parse().unwrap(), false);
+        let (output, verdicts) = filter.filter(b"out\n  {\"result\": tr");
+        assert_eq!((output.as_slice(), verdicts.len()), (&b"out\n"[..], 0));
+        let (output, verdicts) = filter.filter(b"ue, \"score\": 3}\n{no}\n");
+        assert_eq!(output, b"{no}\n");
+        assert_eq!(verdicts, ["PASS score: 3"]);
+        let (output, verdicts) = filter.filter(b"  ");
+        assert_eq!((output.len(), verdicts.len()), (0, 0));
+        let (output, verdicts) = filter.flush();
+        assert_eq!((output.as_slice(), verdicts.len()), (&b"  "[..], 0));
+    }
+
+    #[test]
+    fn ansi_split_across_messages() {
+        let mut filter = AnsiFilter::new();
+        assert_eq!(filter.strip(b"a\x1b[3", false), "a");
+        assert_eq!(filter.strip(b"b", true), "b");
+        assert_eq!(filter.strip(b"1mb\x1b[0m", false), "b");
+        assert_eq!(filter.strip(b"\x1b", false), "");
+        assert_eq!(filter.strip(b"]0;title\x07c", false), "c");
+    }
+
+    #[test]
+    fn expand_connect_shorthand() {
+        let parse = |x: &str| expand_shorthand(CliArgs::parse_from(["rtal", "connect", x]));
+        let args = parse("ws://localhost:8008/prefix/sum/free?n=5").unwrap();
+        assert_eq!(
+            args.server_url.as_deref(),
+            Some("ws://localhost:8008/prefix")
+        );
+        match args.command {
+            Command::Connect(x) => {
+                let ConnectArgs {
+                    problem,
+                    service,
+                    service_arg,
+                    ..
+                } = *x;
+                assert_eq!(
+                    (problem.as_str(), service.as_deref()),
+                    ("sum", Some("free"))
+                );
+                assert_eq!(service_arg, [(String::from("n"), String::from("5"))]);
+            }
+            _ => unreachable!(),
+        }
+        for url in [
+            "ws://localhost:8008/",
+            "ws://localhost:8008/sum?=5",
+            "ws://",
+        ] {
+            assert!(matches!(parse(url), Err(RtalError::Usage(_))), "{}", url);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn scorer_code_in_message() {
+        let transcript = b"C>S 2024-01-01T00:00:00+00:00 3\nabc\n".to_vec();
+        assert!(run_scorer("cat > /dev/null", transcript.clone())
+            .await
+            .is_ok());
+        let error = run_scorer("cat > /dev/null; exit 3", transcript)
+            .await
+            .unwrap_err();
+        assert_eq!(error.exit_code(), 42);
+        assert_eq!(
+            error.to_string(),
+            "Scorer rejected the session with exit code 3"
+        );
+    }
+
+    #[test]
+    fn e2e_seal_and_open() {
+        let key = E2eKey::new(b"secret\n");
+        let sealed = key.seal(Direction::ToServer, b"hello".to_vec());
+        assert_ne!(&sealed[sealed.len() - 5..], b"hello");
+        assert_eq!(
+            E2eKey::new(b"secret")
+                .open(Direction::ToServer, &sealed)
+                .unwrap(),
+            b"hello"
+        );
+        assert_ne!(key.seal(Direction::ToServer, b"hello".to_vec()), sealed);
+        assert!(key.open(Direction::ToClient, &sealed).is_err());
+        assert!(E2eKey::new(b"other")
+            .open(Direction::ToServer, &sealed)
+            .is_err());
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(key.open(Direction::ToServer, &tampered).is_err());
+        assert!(key.open(Direction::ToServer, &sealed[..20]).is_err());
+        let empty = key.seal(Direction::ToClient, Vec::new());
+        assert_eq!(key.open(Direction::ToClient, &empty).unwrap(), b"");
+    }
+
+    #[test]
+    fn print_request_headers_once() {
+        let mut request = "ws://user@localhost:8008/rtal?x=1"
+            .into_client_request()
+            .unwrap();
+        request
+            .headers_mut()
+            .insert("Authorization", "Bearer secret".parse().unwrap());
+        let mut out = Vec::new();
+        print_request(&mut out, &request, &PrintRequest::Redacted).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines[0], "GET /rtal?x=1 HTTP/1.1");
+        for name in [
+            "host",
+            "connection",
+            "upgrade",
+            "sec-websocket-version",
+            "sec-websocket-key",
+        ] {
+            let count = lines
+                .iter()
+                .filter(|x| x.to_lowercase().starts_with(&format!("{}:", name)))
+                .count();
+            assert_eq!(count, 1, "{}", name);
+        }
+        assert!(lines.contains(&"host: localhost:8008"));
+        assert!(lines.contains(&"authorization: <redacted>"));
+        assert_eq!(lines.last(), Some(&""));
+        let mut out = Vec::new();
+        print_request(&mut out, &request, &PrintRequest::Full).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains("authorization: Bearer secret\n"));
+    }
+
+    /// The server end of a session run by a test, seeing what the client sends.
+    struct ServerEnd(mpsc::UnboundedSender<Message>);
+
+    impl Sink<Message> for ServerEnd {
+        type Error = String;
+
+        fn poll_ready(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context,
+        ) -> std::task::Poll<Result<(), String>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: std::pin::Pin<&mut Self>, item: Message) -> Result<(), String> {
+            self.0.send(item).map_err(|x| x.to_string())
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context,
+        ) -> std::task::Poll<Result<(), String>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context,
+        ) -> std::task::Poll<Result<(), String>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    type ClientEnd = std::pin::Pin<Box<dyn Stream<Item = Result<Message, TsError>>>>;
+
+    /// Both directions of a fake connection: what the client sends and what the server sends.
+    fn connection() -> (
+        ServerEnd,
+        mpsc::UnboundedReceiver<Message>,
+        ClientEnd,
+        mpsc::UnboundedSender<Message>,
+    ) {
+        let (wsout, sent) = mpsc::unbounded_channel();
+        let (replies, wsin) = mpsc::unbounded_channel();
+        let wsin = futures_util::stream::unfold(wsin, |mut x| async move {
+            x.recv().await.map(|y| (Ok(y), x))
+        });
+        (ServerEnd(wsout), sent, Box::pin(wsin), replies)
+    }
+
+    fn session_options() -> SessionOptions {
+        SessionOptions {
+            echo: false,
+            colored: false,
+            verdict: None,
+            normalize_newlines: false,
+            eof_marker: None,
+            max_frame_payload: None,
+            max_first_response: None,
+            echo_wrap: None,
+            echo_filter: EchoFilter::Both,
+            echo_strip_ansi: false,
+            echo_overflow: EchoOverflow::Block,
+            idle_warning: None,
+            deadline: None,
+            line_mode: false,
+            loop_limit: None,
+            strict_utf8: false,
+            chaos: None,
+            fail_fast: None,
+            post_connect_delay: None,
+            crash_grace: None,
+            debug_seq_flush: None,
+            ping_interval: None,
+            ping_failures: 1,
+            pausable: false,
+            pause_inbound: false,
+            outbound: OutboundRules::default(),
+            framing: Framing::default(),
+            e2e: None,
+        }
+    }
+
+    fn session_log() -> SessionLog {
+        SessionLog {
+            debug_seq: None,
+            transcript: None,
+            mirror: None,
+            mirror_dropped: 0,
+        }
+    }
+
+    fn stop_reply() -> Message {
+        Message::Text(Reply::ConnectStop { status: Ok(vec![]) }.forge().unwrap())
+    }
+
+    fn is_request(msg: Option<Message>, name: &str) -> bool {
+        matches!(msg, Some(Message::Text(x)) if x.contains(name))
+    }
+
+    #[tokio::test]
+    async fn e2e_session_rejects_unsealed_reply() {
+        let (mut wsout, mut sent, mut wsin, replies) = connection();
+        let key = Arc::new(E2eKey::new(b"secret"));
+        let options = SessionOptions {
+            e2e: Some(key.clone()),
+            ..session_options()
+        };
+        let mut stats = SessionStats::default();
+        let mut log = session_log();
+        let (mut input, pipein) = tokio::io::duplex(64);
+        input.write_all(b"hello\n").await.unwrap();
+        let (output, mut received) = tokio::io::duplex(64);
+        let server = async {
+            match sent.recv().await {
+                Some(Message::Binary(x)) => {
+                    assert_eq!(key.open(Direction::ToServer, &x).unwrap(), b"hello\n")
+                }
+                x => panic!("Unexpected message {:?}", x),
+            }
+            let reply = key.seal(Direction::ToClient, b"bye\n".to_vec());
+            replies.send(Message::Binary(reply)).unwrap();
+            replies.send(Message::Binary(b"forged\n".to_vec())).unwrap();
+        };
+        let (result, _) = tokio::join!(
+            handle_connection(
+                &mut wsout, &mut wsin, pipein, output, None, &options, &mut stats, &mut log,
+            ),
+            server
+        );
+        assert!(matches!(result, Err(RtalError::Integrity(_))));
+        let mut buffer = [0; 4];
+        received.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"bye\n");
+    }
+
+    #[tokio::test]
+    async fn debug_seq_keeps_last_message() {
+        let (mut wsout, mut sent, mut wsin, replies) = connection();
+        let options = session_options();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("seq.log");
+        let mut stats = SessionStats::default();
+        let mut log = SessionLog {
+            debug_seq: Some(BufWriter::new(
+                tokio::fs::File::create(&path).await.unwrap(),
+            )),
+            ..session_log()
+        };
+        // The input stays open, the session ends when the server says so
+        let (mut input, pipein) = tokio::io::duplex(64);
+        input.write_all(b"hello\n").await.unwrap();
+        let server = async {
+            assert_eq!(
+                sent.recv().await,
+                Some(Message::Binary(b"hello\n".to_vec()))
+            );
+            replies.send(Message::Binary(b"bye\n".to_vec())).unwrap();
+            replies.send(stop_reply()).unwrap();
+        };
+        let (result, _) = tokio::join!(
+            handle_connection(
+                &mut wsout,
+                &mut wsin,
+                pipein,
+                tokio::io::sink(),
+                None,
+                &options,
+                &mut stats,
+                &mut log,
+            ),
+            server
+        );
+        assert!(result.is_ok());
+        log.finish().await;
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("1 C>S "));
+        assert!(lines[1].starts_with("1 S>C ") && lines[1].ends_with(" 4 \"bye\\n\""));
+    }
+
+    #[tokio::test]
+    async fn mirror_drops_when_full() {
+        let (sender, mut receiver) = mpsc::channel(1);
+        let mut log = SessionLog {
+            mirror: Some(sender),
+            ..session_log()
+        };
+        log.record(1, "C>S", b"a\n").await;
+        log.record(1, "S>C", b"b\n").await;
+        log.record(2, "C>S", b"c\n").await;
+        assert_eq!(log.mirror_dropped, 2);
+        let event: serde_json::Value =
+            serde_json::from_str(&receiver.recv().await.unwrap()).unwrap();
+        assert_eq!(event["seq"], 1);
+        assert_eq!(event["direction"], "C>S");
+        assert_eq!(event["data"], "a\n");
+        log.record(3, "C>S", b"d\n").await;
+        let event: serde_json::Value =
+            serde_json::from_str(&receiver.recv().await.unwrap()).unwrap();
+        assert_eq!(event["seq"], 3);
+        drop(receiver);
+        log.record(4, "C>S", b"e\n").await;
+        assert!(log.mirror.is_none());
+    }
+
+    #[tokio::test]
+    async fn outbound_rules_per_line() {
+        let (mut wsout, mut sent, mut wsin, _replies) = connection();
+        let options = SessionOptions {
+            outbound: OutboundRules {
+                prefix: Some(String::from(">")),
+                ..Default::default()
+            },
+            ..session_options()
+        };
+        let mut stats = SessionStats::default();
+        let mut log = session_log();
+        // The first line comes in two reads, the second one would not pass alone
+        let (mut input, pipein) = tokio::io::duplex(64);
+        let program = async {
+            for data in [&b">a"[..], b"b\n", b"d\n"] {
+                input.write_all(data).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            input
+        };
+        let (result, _input) = tokio::join!(
+            handle_connection(
+                &mut wsout,
+                &mut wsin,
+                pipein,
+                tokio::io::sink(),
+                None,
+                &options,
+                &mut stats,
+                &mut log,
+            ),
+            program
+        );
+        match result {
+            Err(RtalError::InvalidOutbound(x)) => assert!(x.contains("\"d\\n\""), "{}", x),
+            x => panic!("{:?}", x),
+        }
+        assert_eq!(sent.try_recv(), Ok(Message::Binary(b">ab\n".to_vec())));
+        assert!(sent.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn debug_seq_flushed_during_session() {
+        let (mut wsout, _sent, mut wsin, replies) = connection();
+        let options = SessionOptions {
+            debug_seq_flush: Some(0.1),
+            ..session_options()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("seq.log");
+        let mut stats = SessionStats::default();
+        let mut log = SessionLog {
+            debug_seq: Some(BufWriter::new(
+                tokio::fs::File::create(&path).await.unwrap(),
+            )),
+            ..session_log()
+        };
+        let (_input, pipein) = tokio::io::duplex(64);
+        let server = async {
+            replies.send(Message::Binary(b"hi\n".to_vec())).unwrap();
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            let text = std::fs::read_to_string(&path).unwrap();
+            replies.send(stop_reply()).unwrap();
+            text
+        };
+        let (result, text) = tokio::join!(
+            handle_connection(
+                &mut wsout,
+                &mut wsin,
+                pipein,
+                tokio::io::sink(),
+                None,
+                &options,
+                &mut stats,
+                &mut log,
+            ),
+            server
+        );
+        assert!(result.is_ok());
+        assert!(text.starts_with("1 S>C ") && text.ends_with(" 3 \"hi\\n\"\n"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn crash_grace_records_server_reply() {
+        let (mut wsout, mut sent, mut wsin, replies) = connection();
+        let options = SessionOptions {
+            crash_grace: Some(5000),
+            ..session_options()
+        };
+        let mut stats = SessionStats::default();
+        let (mirror, mut observer) = mpsc::channel(8);
+        let mut log = SessionLog {
+            mirror: Some(mirror),
+            ..session_log()
+        };
+        let mut child = proc::Command::new("sh")
+            .args(["-c", "exit 3"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let pipein = child.stdout.take().unwrap();
+        let pipeout = child.stdin.take().unwrap();
+        let server = async {
+            assert!(is_request(sent.recv().await, "ConnectEof"));
+            replies
+                .send(Message::Binary(b"Runtime error\n".to_vec()))
+                .unwrap();
+            replies.send(stop_reply()).unwrap();
+            assert!(is_request(sent.recv().await, "ConnectStop"));
+        };
+        let (result, _) = tokio::join!(
+            handle_connection(
+                &mut wsout,
+                &mut wsin,
+                pipein,
+                pipeout,
+                Some(&mut child),
+                &options,
+                &mut stats,
+                &mut log,
+            ),
+            server
+        );
+        assert!(result.is_ok());
+        assert_eq!(stats.received.messages, 1);
+        let event: serde_json::Value =
+            serde_json::from_str(&observer.recv().await.unwrap()).unwrap();
+        assert_eq!(event["data"], "Runtime error\n");
+    }
+
+    #[tokio::test]
+    async fn eof_marker_keeps_reading_replies() {
+        let (mut wsout, mut sent, mut wsin, replies) = connection();
+        let options = SessionOptions {
+            eof_marker: Some(String::from("EOF")),
+            ..session_options()
+        };
+        let mut stats = SessionStats::default();
+        let mut log = session_log();
+        let mut output = Vec::new();
+        let server = async {
+            assert_eq!(sent.recv().await, Some(Message::Binary(b"a\n".to_vec())));
+            assert!(is_request(sent.recv().await, "ConnectEof"));
+            replies.send(Message::Binary(b"result\n".to_vec())).unwrap();
+            replies.send(stop_reply()).unwrap();
+            assert!(is_request(sent.recv().await, "ConnectStop"));
+        };
+        let (result, _) = tokio::join!(
+            handle_connection(
+                &mut wsout,
+                &mut wsin,
+                &b"a\nEOF\nb\n"[..],
+                &mut output,
+                None,
+                &options,
+                &mut stats,
+                &mut log,
+            ),
+            server
+        );
+        assert!(result.is_ok());
+        assert_eq!(output, b"result\n");
+        assert_eq!(stats.sent.messages, 1);
+    }
 }
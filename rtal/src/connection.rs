@@ -1,6 +1,7 @@
+use crate::e2e::{Direction, E2eKey};
 use crate::problem::{load, load_all_meta, Problem, Service};
 use crate::proto::{self, Reply, Request};
-use crate::util::{recv_binary_data, send_binary_data};
+use crate::util::{recv_binary_data, send_binary_data, send_compressed_binary_data};
 use crate::CliArgs;
 use futures_util::sink::Sink;
 use futures_util::stream::Stream;
@@ -203,7 +204,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Client<T> {
                         meta: load_all_meta(&self.args.directory).await
                     }
                 ),
-                Request::Attachment { problem } => {
+                Request::Attachment { problem, compress } => {
                     let problem = sanitize(problem);
                     let meta = match load(&self.args.directory, &problem).await {
                         Some(x) => x,
@@ -232,9 +233,13 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Client<T> {
                         }
                     };
                     wssend!(wsout, Reply::Attachment { status: Ok(()) });
-                    if let Err(x) =
-                        send_binary_data(&mut wsout, &format!("{}.tar", problem), &tar).await
-                    {
+                    let name = format!("{}.tar", problem);
+                    let result = if compress {
+                        send_compressed_binary_data(&mut wsout, &name, &tar).await
+                    } else {
+                        send_binary_data(&mut wsout, &name, &tar).await
+                    };
+                    if let Err(x) = result {
                         warn!("Cannot send attachment of problem {}: {}", problem, x);
                         break;
                     }
@@ -350,6 +355,22 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Client<T> {
                         );
                         continue;
                     }
+                    let e2e = match self.args.e2e_key_file {
+                        Some(ref x) => match E2eKey::load(x).await {
+                            Ok(x) => Some(x),
+                            Err(x) => {
+                                error!("Cannot load the end-to-end key: {}", x);
+                                wssend!(
+                                    wsout,
+                                    Reply::ConnectBegin {
+                                        status: Err("The server cannot seal the session".to_string())
+                                    }
+                                );
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
                     wssend!(
                         wsout,
                         Reply::ConnectBegin {
@@ -368,6 +389,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Client<T> {
                         self.args.timeout,
                         files,
                         &self.address,
+                        e2e.as_ref(),
                     )
                     .await
                     {
@@ -397,6 +419,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Client<T> {
         conn_timeout: f64,
         declared_files: Vec<String>,
         address: &str,
+        e2e: Option<&E2eKey>,
     ) -> Result<(), String>
     where
         <X as Sink<Message>>::Error: Display,
@@ -543,7 +566,13 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Client<T> {
                         }
                     };
                     let msg = match handle_ping!(msg, wsout) {
-                        Some(Ok(Message::Binary(x))) => x,
+                        Some(Ok(Message::Binary(x))) => match e2e {
+                            Some(key) => match key.open(Direction::ToServer, &x) {
+                                Ok(x) => x,
+                                Err(x) => return Err(format!("Cannot open client message: {}", x)),
+                            },
+                            None => x,
+                        },
                         Some(Ok(Message::Text(x))) => match Request::parse(&x) {
                             Ok(Request::ConnectStop {}) => {
                                 ev_stdin = None;
@@ -551,6 +580,10 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Client<T> {
                                 client_closed = true;
                                 continue;
                             }
+                            Ok(Request::ConnectEof {}) => {
+                                ev_stdin = None;
+                                continue;
+                            }
                             Ok(_) => {
                                 warn!("Invalid command sent");
                                 return Ok(());
@@ -575,7 +608,11 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Client<T> {
                     if ev_stdout_ignore {
                         continue;
                     }
-                    if let Err(x) = wsout.send(Message::Binary(buffer[..size].into())).await {
+                    let data = match e2e {
+                        Some(key) => key.seal(Direction::ToClient, buffer[..size].into()),
+                        None => buffer[..size].into(),
+                    };
+                    if let Err(x) = wsout.send(Message::Binary(data)).await {
                         warn!("Lost connection while communicating: {}", x);
                         return Ok(());
                     }
@@ -0,0 +1,86 @@
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::Path;
+
+/// Side that sealed a message, bound to it so that a message cannot be sent back to its sender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ToServer,
+    ToClient,
+}
+
+impl Direction {
+    fn label(self) -> &'static [u8] {
+        match self {
+            Direction::ToServer => b"rtal e2e C>S",
+            Direction::ToClient => b"rtal e2e S>C",
+        }
+    }
+}
+
+/// Pre-shared key of --e2e-key-file, sealing the session messages with ChaCha20-Poly1305.
+/// Every sealed message is a random nonce followed by the ciphertext and its tag.
+pub struct E2eKey {
+    key: LessSafeKey,
+}
+
+impl fmt::Debug for E2eKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("E2eKey")
+    }
+}
+
+impl E2eKey {
+    /// Derives the key from the contents of a key file, whatever their length.
+    pub fn new(material: &[u8]) -> E2eKey {
+        let material = material.strip_suffix(b"\n").unwrap_or(material);
+        let digest = Sha256::digest(material);
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &digest).expect("Cannot fail");
+        E2eKey {
+            key: LessSafeKey::new(key),
+        }
+    }
+
+    pub async fn load(path: &Path) -> Result<E2eKey, String> {
+        match tokio::fs::read(path).await {
+            Ok(x) if x.is_empty() => Err(format!("Key file \"{}\" is empty", path.display())),
+            Ok(x) => Ok(E2eKey::new(&x)),
+            Err(x) => Err(format!("Cannot read \"{}\": {}", path.display(), x)),
+        }
+    }
+
+    pub fn seal(&self, direction: Direction, mut data: Vec<u8>) -> Vec<u8> {
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(direction.label()),
+                &mut data,
+            )
+            .expect("Cannot fail");
+        let mut sealed = nonce.to_vec();
+        sealed.append(&mut data);
+        sealed
+    }
+
+    pub fn open(&self, direction: Direction, data: &[u8]) -> Result<Vec<u8>, String> {
+        if data.len() < NONCE_LEN + CHACHA20_POLY1305.tag_len() {
+            return Err(format!("Sealed message too short ({} bytes)", data.len()));
+        }
+        let (nonce, sealed) = data.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).expect("Cannot fail");
+        let mut sealed = sealed.to_vec();
+        match self
+            .key
+            .open_in_place(nonce, Aad::from(direction.label()), &mut sealed)
+        {
+            Ok(x) => Ok(x.to_vec()),
+            Err(_) => {
+                Err("Message failed authentication, is the key the same on both sides?".to_string())
+            }
+        }
+    }
+}
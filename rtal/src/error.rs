@@ -0,0 +1,92 @@
+use std::error::Error;
+use std::fmt::{self, Display};
+
+/// Reasons for which the client can fail, each carrying a human readable message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RtalError {
+    /// The connection to the server could not be established or configured
+    SocketConfig(String),
+    /// The server did not complete the TALight handshake
+    Handshake(String),
+    /// The server did not accept the challenge-response authentication
+    Auth(String),
+    /// A file received from the server did not match its hash, or a sealed message did not
+    /// authenticate
+    Integrity(String),
+    /// A local file, pipe or stream could not be used
+    Io(String),
+    /// An option or a file given on the command line is not valid
+    Usage(String),
+    /// The local program could not be started
+    ProcessSpawn(String),
+    /// The server did not answer in time
+    Timeout(String),
+    /// The session reached its deadline
+    Deadline(String),
+    /// The connection with the server was lost
+    PeerClosed(String),
+    /// The server stopped answering the liveness pings
+    PeerUnresponsive(String),
+    /// The server refused a request or sent an unexpected reply
+    Server(String),
+    /// The session ended before the server sent enough data
+    TooLittleReceived(String),
+    /// The local scorer did not accept the session
+    Scorer(String),
+    /// The same message kept bouncing between the server and the program
+    LoopDetected(String),
+    /// The program sent a message breaking the outbound rules
+    InvalidOutbound(String),
+    /// The user interrupted the client
+    Interrupted(String),
+}
+
+impl Display for RtalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RtalError::SocketConfig(x)
+            | RtalError::Handshake(x)
+            | RtalError::Auth(x)
+            | RtalError::Io(x)
+            | RtalError::Usage(x)
+            | RtalError::Integrity(x)
+            | RtalError::ProcessSpawn(x)
+            | RtalError::Timeout(x)
+            | RtalError::Deadline(x)
+            | RtalError::PeerClosed(x)
+            | RtalError::PeerUnresponsive(x)
+            | RtalError::Server(x)
+            | RtalError::TooLittleReceived(x)
+            | RtalError::Scorer(x)
+            | RtalError::LoopDetected(x)
+            | RtalError::InvalidOutbound(x)
+            | RtalError::Interrupted(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+impl RtalError {
+    /// Process exit code for the error, the table in README.md must be kept in sync.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RtalError::Io(_) => 1,
+            RtalError::Usage(_) => 2,
+            RtalError::Timeout(_) => 10,
+            RtalError::Deadline(_) => 11,
+            RtalError::PeerClosed(_) | RtalError::Server(_) => 20,
+            RtalError::Handshake(_) => 21,
+            RtalError::SocketConfig(_) => 22,
+            RtalError::Auth(_) => 23,
+            RtalError::PeerUnresponsive(_) => 24,
+            RtalError::Integrity(_) => 25,
+            RtalError::ProcessSpawn(_) => 30,
+            RtalError::TooLittleReceived(_) => 40,
+            RtalError::LoopDetected(_) => 41,
+            RtalError::Scorer(_) => 42,
+            RtalError::InvalidOutbound(_) => 43,
+            RtalError::Interrupted(_) => 130,
+        }
+    }
+}
+
+impl Error for RtalError {}
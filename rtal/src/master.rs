@@ -1,4 +1,5 @@
 use crate::connection::Client;
+use crate::proto;
 use crate::CliArgs;
 use std::error::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
@@ -6,6 +7,7 @@ use tokio::net::TcpListener;
 use tokio::spawn;
 use tokio_tungstenite::accept_hdr_async;
 use tokio_tungstenite::tungstenite::handshake::server as tuns;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
 use tracing::{error, info, warn};
 
 #[cfg(unix)]
@@ -30,6 +32,11 @@ async fn handle_raw_socket<T: AsyncRead + AsyncWrite + Unpin>(
                 address = x.to_string();
             }
         }
+        let mut response = response;
+        response.headers_mut().insert(
+            proto::VERSION_HEADER,
+            HeaderValue::from_static(env!("CARGO_PKG_VERSION")),
+        );
         Ok(response)
     };
     let websocket = match accept_hdr_async(socket, headers_callback).await {
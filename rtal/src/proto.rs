@@ -4,6 +4,8 @@ use std::collections::HashMap;
 
 pub const MAGIC: &str = "rtal";
 pub const VERSION: u64 = 4;
+/// Response header the server uses to tell its release in the WebSocket handshake.
+pub const VERSION_HEADER: &str = "x-rtal-version";
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
@@ -14,6 +16,8 @@ pub enum Request {
     MetaList {},
     Attachment {
         problem: String,
+        #[serde(default)]
+        compress: bool,
     },
     ConnectBegin {
         problem: String,
@@ -24,6 +28,8 @@ pub enum Request {
         files: Vec<String>,
     },
     ConnectStop {},
+    /// The client sends nothing more, the evaluator keeps talking until it exits.
+    ConnectEof {},
 }
 
 #[derive(Serialize, Deserialize, Debug)]
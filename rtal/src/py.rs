@@ -91,6 +91,9 @@ fn recv_binary_data(
     };
     let (name, size, hash) = match serde_json::from_str::<StreamMessage>(&header) {
         Ok(StreamMessage::BinaryDataHeader { name, size, hash }) => (name, size, hash),
+        Ok(StreamMessage::CompressedBinaryDataHeader { .. }) => {
+            return Err(format!("Received unexpected compressed binary data"))
+        }
         Err(x) => return Err(format!("Received invalid binary header: {}", x)),
     };
     let mut buffer = Vec::new();
@@ -284,6 +287,7 @@ impl RTAL {
     fn get<'p>(&mut self, problem: &str, py: Python<'p>) -> PyResult<(String, &'p PyBytes)> {
         let request = Request::Attachment {
             problem: problem.into(),
+            compress: false,
         };
         match oneshot_request(request, &mut self.ws).map_err(|x| PRE::new_err(x))? {
             Reply::Attachment { status: Ok(()) } => {}
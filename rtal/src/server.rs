@@ -1,4 +1,5 @@
 mod connection;
+mod e2e;
 mod master;
 mod problem;
 mod proto;
@@ -29,6 +30,11 @@ struct CliArgs {
     authentication: Option<PathBuf>,
     #[clap(short = 'x', long, help = "Accept only authenticated connections")]
     only_auth: bool,
+    #[clap(
+        long,
+        help = "Seal the session messages with ChaCha20-Poly1305 keyed by this file, the clients need the same --e2e-key-file"
+    )]
+    e2e_key_file: Option<PathBuf>,
     #[cfg(unix)]
     #[clap(short, long, help = "Use bind address as a Unix Domain Socket")]
     unix_domain_socket: bool,
@@ -1,7 +1,12 @@
+use flate2::write::{GzDecoder, GzEncoder};
+use flate2::Compression;
 use futures_util::{Sink, SinkExt, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::fmt::Display;
+use std::io::Write;
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
 use tokio_tungstenite::tungstenite::Error as TsError;
 use tokio_tungstenite::tungstenite::Message;
 
@@ -14,6 +19,23 @@ pub enum StreamMessage {
         size: usize,
         hash: [u8; 32],
     },
+    CompressedBinaryDataHeader {
+        name: String,
+        size: usize,
+        hash: [u8; 32],
+    },
+}
+
+/// Part of `recv_binary_file` that failed.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum RecvFileError {
+    /// The transfer was interrupted or did not follow the protocol
+    Transfer(String),
+    /// The file could not be placed or written
+    Write(String),
+    /// The data did not match the hash announced in the header
+    Corrupted(String),
 }
 
 pub async fn send_binary_data<T: Sink<Message> + Unpin>(
@@ -45,9 +67,48 @@ where
     Ok(())
 }
 
-pub async fn recv_binary_data<U: Stream<Item = Result<Message, TsError>> + Unpin>(
+// Only rtald sends compressed data
+#[allow(dead_code)]
+pub async fn send_compressed_binary_data<T: Sink<Message> + Unpin>(
+    wsout: &mut T,
+    name: &str,
+    data: &[u8],
+) -> Result<(), String>
+where
+    <T as Sink<Message>>::Error: Display,
+{
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    if let Err(x) = encoder.write_all(data) {
+        return Err(format!("Cannot compress binary data: {}", x));
+    }
+    let compressed = match encoder.finish() {
+        Ok(x) => x,
+        Err(x) => return Err(format!("Cannot compress binary data: {}", x)),
+    };
+    let header = StreamMessage::CompressedBinaryDataHeader {
+        name: name.to_string(),
+        size: compressed.len(),
+        hash: Sha256::digest(data).into(),
+    };
+    let serialized_header = match serde_json::to_string(&header) {
+        Ok(x) => x,
+        Err(x) => return Err(format!("Cannot serialize binary header: {}", x)),
+    };
+    if let Err(x) = wsout.send(Message::Text(serialized_header)).await {
+        return Err(format!("Cannot send binary header: {}", x));
+    }
+    for offset in (0..compressed.len()).step_by(BUFFER_SIZE) {
+        let slice = &compressed[offset..compressed.len().min(offset + BUFFER_SIZE)];
+        if let Err(x) = wsout.send(Message::Binary(slice.to_vec())).await {
+            return Err(format!("Cannot send binary data: {}", x));
+        }
+    }
+    Ok(())
+}
+
+async fn recv_binary_header<U: Stream<Item = Result<Message, TsError>> + Unpin>(
     wsin: &mut U,
-) -> Result<(String, Vec<u8>), String> {
+) -> Result<StreamMessage, String> {
     let header = loop {
         match wsin.next().await {
             Some(Ok(Message::Text(x))) => break x,
@@ -60,25 +121,40 @@ pub async fn recv_binary_data<U: Stream<Item = Result<Message, TsError>> + Unpin
             }
         }
     };
-    let (name, size, hash) = match serde_json::from_str::<StreamMessage>(&header) {
-        Ok(StreamMessage::BinaryDataHeader { name, size, hash }) => (name, size, hash),
-        Err(x) => return Err(format!("Received invalid binary header: {}", x)),
+    serde_json::from_str::<StreamMessage>(&header)
+        .map_err(|x| format!("Received invalid binary header: {}", x))
+}
+
+async fn recv_binary_chunk<U: Stream<Item = Result<Message, TsError>> + Unpin>(
+    wsin: &mut U,
+) -> Result<Vec<u8>, String> {
+    loop {
+        match wsin.next().await {
+            Some(Ok(Message::Binary(x))) => break Ok(x),
+            Some(Ok(_)) => continue,
+            Some(Err(x)) => break Err(format!("Error while receiving binary data: {}", x)),
+            None => break Err("Connection interrupted while waiting for binary data".to_string()),
+        }
+    }
+}
+
+// Only rtald receives files it keeps in memory
+#[allow(dead_code)]
+pub async fn recv_binary_data<U: Stream<Item = Result<Message, TsError>> + Unpin>(
+    wsin: &mut U,
+) -> Result<(String, Vec<u8>), String> {
+    let (name, size, hash) = match recv_binary_header(wsin).await? {
+        StreamMessage::BinaryDataHeader { name, size, hash } => (name, size, hash),
+        StreamMessage::CompressedBinaryDataHeader { .. } => {
+            return Err("Received unexpected compressed binary data".to_string())
+        }
     };
     let mut buffer = Vec::new();
     loop {
         if buffer.len() >= size {
             break;
         }
-        let mut data = match wsin.next().await {
-            Some(Ok(Message::Binary(x))) => x,
-            Some(Ok(_)) => continue,
-            Some(Err(x)) => return Err(format!("Error while receiving binary data: {}", x)),
-            None => {
-                return Err(format!(
-                    "Connection interrupted while waiting for binary data"
-                ))
-            }
-        };
+        let mut data = recv_binary_chunk(wsin).await?;
         buffer.append(&mut data);
     }
     if Into::<[u8; 32]>::into(Sha256::digest(&buffer)) != hash {
@@ -86,3 +162,90 @@ pub async fn recv_binary_data<U: Stream<Item = Result<Message, TsError>> + Unpin
     }
     Ok((name, buffer))
 }
+
+// Only rtal saves files to disk
+#[allow(dead_code)]
+pub async fn recv_binary_file<
+    U: Stream<Item = Result<Message, TsError>> + Unpin,
+    F: FnOnce(&str) -> Result<PathBuf, String>,
+>(
+    wsin: &mut U,
+    path: F,
+) -> Result<PathBuf, RecvFileError> {
+    let (name, size, hash, compressed) = match recv_binary_header(wsin)
+        .await
+        .map_err(RecvFileError::Transfer)?
+    {
+        StreamMessage::BinaryDataHeader { name, size, hash } => (name, size, hash, false),
+        StreamMessage::CompressedBinaryDataHeader { name, size, hash } => (name, size, hash, true),
+    };
+    let path = path(&name).map_err(RecvFileError::Write)?;
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(|x| RecvFileError::Write(format!("Cannot create {:?}: {}", parent, x)))?;
+    }
+    // Written aside and renamed once verified, so that a failed transfer leaves no file behind
+    let mut partial = path.clone().into_os_string();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    let result: Result<(), RecvFileError> = async {
+        let mut file = tokio::fs::File::create(&partial)
+            .await
+            .map_err(|x| RecvFileError::Write(format!("Cannot create {:?}: {}", partial, x)))?;
+        let mut decoder = GzDecoder::new(Vec::new());
+        let mut hasher = Sha256::new();
+        let mut received = 0;
+        while received < size {
+            let data = recv_binary_chunk(wsin)
+                .await
+                .map_err(RecvFileError::Transfer)?;
+            received += data.len();
+            let data = if compressed {
+                if let Err(x) = decoder.write_all(&data) {
+                    return Err(RecvFileError::Corrupted(format!(
+                        "Cannot decompress binary data: {}",
+                        x
+                    )));
+                }
+                std::mem::take(decoder.get_mut())
+            } else {
+                data
+            };
+            hasher.update(&data);
+            file.write_all(&data)
+                .await
+                .map_err(|x| RecvFileError::Write(format!("Cannot write {:?}: {}", partial, x)))?;
+        }
+        let decoded = match decoder.finish() {
+            Ok(x) => x,
+            Err(x) => {
+                return Err(RecvFileError::Corrupted(format!(
+                    "Cannot decompress binary data: {}",
+                    x
+                )))
+            }
+        };
+        hasher.update(&decoded);
+        file.write_all(&decoded)
+            .await
+            .map_err(|x| RecvFileError::Write(format!("Cannot write {:?}: {}", partial, x)))?;
+        file.flush()
+            .await
+            .map_err(|x| RecvFileError::Write(format!("Cannot write {:?}: {}", partial, x)))?;
+        if Into::<[u8; 32]>::into(hasher.finalize()) != hash {
+            return Err(RecvFileError::Corrupted(
+                "Received corrupted binary data".to_string(),
+            ));
+        }
+        tokio::fs::rename(&partial, &path)
+            .await
+            .map_err(|x| RecvFileError::Write(format!("Cannot create {:?}: {}", path, x)))
+    }
+    .await;
+    if let Err(x) = result {
+        drop(tokio::fs::remove_file(&partial).await);
+        return Err(x);
+    }
+    Ok(path)
+}